@@ -0,0 +1,150 @@
+extern crate libc;
+
+/// ELFAux IDs and values
+pub const AT_NULL: u64 =   0;
+pub const AT_EXECFD: u64 =   2;
+pub const AT_PHDR: u64 =    3;
+pub const AT_PHENT: u64 =   4;
+pub const AT_PHNUM: u64 =   5;
+pub const AT_PAGESZ: u64 =  6;
+pub const AT_BASE: u64 =   7;
+pub const AT_FLAGS: u64 =   8;
+pub const AT_ENTRY: u64 =   9;
+pub const AT_UID: u64 =   11;
+pub const AT_EUID: u64 =   12;
+pub const AT_GID: u64 =   13;
+pub const AT_EGID: u64 =   14;
+pub const AT_PLATFORM: u64 =   15;
+pub const AT_HWCAP: u64 =   16;
+pub const AT_CLKTCK: u64 =  17;
+pub const AT_SECURE: u64 =   23;
+pub const AT_BASE_PLATFORM: u64 =   24;
+pub const AT_RANDOM: u64 =   25;
+pub const AT_HWCAP2: u64 =   26;
+pub const AT_RSEQ_FEATURE_SIZE: u64 =   27;
+pub const AT_RSEQ_ALIGN: u64 =   28;
+pub const AT_HWCAP3: u64 =   29;
+pub const AT_HWCAP4: u64 =   30;
+pub const AT_EXECFN: u64 =   31;
+pub const AT_SYSINFO: u64 =   32;
+pub const AT_SYSINFO_EHDR: u64 = 33;
+pub const AT_MINSIGSTKSZ: u64 =   51;
+
+
+/// the names of the entries, as used on the command line
+const AUX_NAMES: [(&str, u64); 28] = [
+    ("AT_NULL", AT_NULL),
+    ("AT_EXECFD", AT_EXECFD),
+    ("AT_PHDR", AT_PHDR),
+    ("AT_PHENT", AT_PHENT),
+    ("AT_PHNUM", AT_PHNUM),
+    ("AT_PAGESZ", AT_PAGESZ),
+    ("AT_BASE", AT_BASE),
+    ("AT_FLAGS", AT_FLAGS),
+    ("AT_ENTRY", AT_ENTRY),
+    ("AT_UID", AT_UID),
+    ("AT_EUID", AT_EUID),
+    ("AT_GID", AT_GID),
+    ("AT_EGID", AT_EGID),
+    ("AT_PLATFORM", AT_PLATFORM),
+    ("AT_HWCAP", AT_HWCAP),
+    ("AT_CLKTCK", AT_CLKTCK),
+    ("AT_SECURE", AT_SECURE),
+    ("AT_BASE_PLATFORM", AT_BASE_PLATFORM),
+    ("AT_RANDOM", AT_RANDOM),
+    ("AT_HWCAP2", AT_HWCAP2),
+    ("AT_RSEQ_FEATURE_SIZE", AT_RSEQ_FEATURE_SIZE),
+    ("AT_RSEQ_ALIGN", AT_RSEQ_ALIGN),
+    ("AT_HWCAP3", AT_HWCAP3),
+    ("AT_HWCAP4", AT_HWCAP4),
+    ("AT_EXECFN", AT_EXECFN),
+    ("AT_SYSINFO", AT_SYSINFO),
+    ("AT_SYSINFO_EHDR", AT_SYSINFO_EHDR),
+    ("AT_MINSIGSTKSZ", AT_MINSIGSTKSZ),
+];
+
+
+/// A change to the aux vector requested by the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuxOverride {
+    /// replace the value of an entry, or append the entry if the kernel did not emit it
+    Set(u64, u64),
+    /// remove an entry
+    Drop(u64),
+}
+
+
+/// the entries create_elf_tables() of the kernel emits, in the order it emits them. The ones marked as
+/// optional are only present if the kernel has a value for them
+const KERNEL_AUXV: [(u64, bool); 26] = [
+    (AT_SYSINFO, true),
+    (AT_SYSINFO_EHDR, true),
+    (AT_MINSIGSTKSZ, true),
+    (AT_HWCAP, false),
+    (AT_PAGESZ, false),
+    (AT_CLKTCK, false),
+    (AT_PHDR, false),
+    (AT_PHENT, false),
+    (AT_PHNUM, false),
+    (AT_BASE, false),
+    (AT_FLAGS, false),
+    (AT_ENTRY, false),
+    (AT_UID, false),
+    (AT_EUID, false),
+    (AT_GID, false),
+    (AT_EGID, false),
+    (AT_SECURE, false),
+    (AT_RANDOM, false),
+    (AT_HWCAP2, true),
+    (AT_HWCAP3, true),
+    (AT_HWCAP4, true),
+    (AT_EXECFN, false),
+    (AT_PLATFORM, true),
+    (AT_BASE_PLATFORM, true),
+    (AT_RSEQ_FEATURE_SIZE, true),
+    (AT_RSEQ_ALIGN, true),
+];
+
+
+/// returns the auxiliary vector the kernel passed to the loader itself, without the terminating AT_NULL.
+/// It tells which entries the running kernel emits and in which order, so that the loaded program can be
+/// given the same ones. If /proc is not available, the vector is rebuilt from getauxval() instead
+pub fn kernel_auxv() -> Vec<(u64, u64)> {
+    match std::fs::read("/proc/self/auxv") {
+        Ok(raw) => raw.chunks_exact(16)
+            .map(|entry| {
+                let mut key = [0u8; 8];
+                let mut val = [0u8; 8];
+                key.copy_from_slice(&entry[..8]);
+                val.copy_from_slice(&entry[8..]);
+                (u64::from_ne_bytes(key), u64::from_ne_bytes(val))
+            })
+            .take_while(|(key, _)| *key != AT_NULL)
+            .collect(),
+        Err(_) => KERNEL_AUXV.iter()
+            .map(|(key, optional)| (*key, *optional, unsafe { libc::getauxval(*key) }))
+            .filter(|(_, optional, val)| !optional || *val != 0)
+            .map(|(key, _, val)| (key, val))
+            .collect(),
+    }
+}
+
+/// translates the name of an entry, with or without the AT_ prefix, or its number into its type
+pub fn aux_type(name: &str) -> Option<u64> {
+    let upper = name.to_ascii_uppercase();
+    let prefixed = if upper.starts_with("AT_") { upper } else { format!("AT_{}", upper) };
+
+    AUX_NAMES.iter()
+        .find(|(aux_name, _)| *aux_name == prefixed)
+        .map(|(_, aux_type)| *aux_type)
+        .or_else(|| name.parse::<u64>().ok())
+        // AT_NULL terminates the vector, it can not be set or dropped
+        .filter(|aux_type| *aux_type != AT_NULL)
+}
+
+/// returns the name of an entry, e.g. AT_PHDR, or None for types this loader does not know
+pub fn aux_name(aux_type: u64) -> Option<&'static str> {
+    AUX_NAMES.iter()
+        .find(|(_, name_type)| *name_type == aux_type)
+        .map(|(name, _)| *name)
+}
@@ -0,0 +1,301 @@
+extern crate libc;
+extern crate nix;
+use nix::sys::mman::{
+    mmap,
+    ProtFlags,
+    MapFlags
+};
+use core::ffi::c_void;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+
+use crate::load_elf::{map_fixed_noreplace, ElfLoad, PAGE_SIZE};
+
+/// the amount of address space reserved for the program break of each image
+pub const DEFAULT_BRK_SIZE: usize = 1 << 30;
+
+/// the maximum number of images that can have their own program break
+const MAX_REGIONS: usize = 8;
+
+const PAGE_MASK: usize = !(PAGE_SIZE - 1);
+
+/// the value of seccomp_data.arch for x86-64 syscalls
+const AUDIT_ARCH_X86_64: u32 = 0xc000003e;
+
+/// the si_code of a SIGSYS that was raised by a SECCOMP_RET_TRAP filter
+const SYS_SECCOMP: i32 = 1;
+
+/// offsets of the nr and arch fields within struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// offset of si_syscall within a siginfo_t raised for SIGSYS
+const SIGINFO_SYSCALL: usize = 24;
+
+
+/// Everything that can go wrong while setting up the program break emulation
+#[derive(Debug)]
+pub enum BrkError {
+    /// the address space for a program break could not be reserved
+    Reserve(nix::Error),
+    /// every slot for a program break is already taken
+    TooManyImages,
+    /// the SIGSYS handler or the seccomp filter could not be installed
+    Install(std::io::Error),
+}
+
+impl fmt::Display for BrkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BrkError::Reserve(err) => write!(f, "failed to reserve a program break: {}", err),
+            BrkError::TooManyImages => write!(f, "at most {} images can have their own program break", MAX_REGIONS),
+            BrkError::Install(err) => write!(f, "failed to intercept brk(): {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BrkError {}
+
+
+/// The emulated program break of a single image. Calls to brk() made by code within [owner_start, owner_end)
+/// are served from [start, end). The fields are atomics, as they are accessed from the signal handler
+struct BrkRegion {
+    taken: AtomicBool,
+    owner_start: AtomicUsize,
+    owner_end: AtomicUsize,
+    start: AtomicUsize,
+    current: AtomicUsize,
+    end: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_REGION: BrkRegion = BrkRegion {
+    taken: AtomicBool::new(false),
+    owner_start: AtomicUsize::new(0),
+    owner_end: AtomicUsize::new(0),
+    start: AtomicUsize::new(0),
+    current: AtomicUsize::new(0),
+    end: AtomicUsize::new(0),
+};
+
+/// all program breaks. The first region that is taken belongs to the program itself and also serves every brk()
+/// call that does not originate from an image the loader mapped, such as the libc loaded by ld.so
+static REGIONS: [BrkRegion; MAX_REGIONS] = [EMPTY_REGION; MAX_REGIONS];
+
+/// the protection of the pages the program break grows into
+static BRK_PROT: AtomicI32 = AtomicI32::new(libc::PROT_READ | libc::PROT_WRITE);
+
+
+/// A program break reserved by reserve(). Dropping it unmaps the region and frees its slot for the next image
+#[derive(Debug)]
+pub struct BrkReservation {
+    idx: usize,
+}
+
+impl Drop for BrkReservation {
+    fn drop(&mut self) {
+        let region = &REGIONS[self.idx];
+        let start = region.start.load(Ordering::SeqCst);
+        let end = region.end.load(Ordering::SeqCst);
+        unsafe {
+            libc::munmap(start as *mut c_void, end - start);
+        }
+        region.taken.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Reserves a program break region of the given size right past the given image. The first image that
+/// is registered is the program itself. If the address right past the image is taken, mmap() chooses
+/// a location instead. The region stays reserved until the returned reservation is dropped, at most
+/// MAX_REGIONS of them can exist at the same time
+pub fn reserve(load: &ElfLoad, size: usize, read_implies_exec: bool) -> Result<BrkReservation, BrkError> {
+    // claim the first free slot, the lowest one is the program itself as long as it is reserved first
+    let idx = REGIONS.iter()
+        .position(|region| region.taken.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok())
+        .ok_or(BrkError::TooManyImages)?;
+
+    // the region is inaccessible until the program break grows into it. MAP_NORESERVE makes sure that
+    // the reservation does not count against the commit limit
+    let mut map_flags = MapFlags::empty();
+    map_flags.insert(MapFlags::MAP_PRIVATE);
+    map_flags.insert(MapFlags::MAP_ANONYMOUS);
+    map_flags.insert(MapFlags::MAP_NORESERVE);
+
+    let region = &REGIONS[idx];
+    let start = unsafe {
+        match mmap(load.load_end as *mut c_void, size, ProtFlags::PROT_NONE, map_flags | map_fixed_noreplace(), -1, 0) {
+            Ok(start) => start,
+            Err(_) => match mmap(std::ptr::null_mut(), size, ProtFlags::PROT_NONE, map_flags, -1, 0) {
+                Ok(start) => start,
+                Err(err) => {
+                    region.taken.store(false, Ordering::SeqCst);
+                    return Err(BrkError::Reserve(err));
+                },
+            },
+        }
+    } as usize;
+
+    region.owner_start.store(load.load_addr, Ordering::SeqCst);
+    region.owner_end.store(load.load_end, Ordering::SeqCst);
+    region.start.store(start, Ordering::SeqCst);
+    region.current.store(start, Ordering::SeqCst);
+    region.end.store(start + size, Ordering::SeqCst);
+
+    let prot = if read_implies_exec {
+        libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC
+    } else {
+        libc::PROT_READ | libc::PROT_WRITE
+    };
+    BRK_PROT.store(prot, Ordering::SeqCst);
+
+    Ok(BrkReservation { idx })
+}
+
+/// Installs the SIGSYS handler and a seccomp filter that traps every brk() syscall. This has to be the
+/// last thing the loader does before jumping to the entry point, since the allocator of the loader itself
+/// uses brk() as well. The filter can not be removed again and is inherited by child processes, so
+/// programs the loaded program executes will be killed once they call brk()
+pub fn install() -> Result<(), BrkError> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigsys as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGSYS, &action, std::ptr::null_mut()) != 0 {
+            return Err(BrkError::Install(std::io::Error::last_os_error()));
+        }
+
+        // only trap native x86-64 brk() calls, let everything else through
+        let mut filter = [
+            bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_ARCH),
+            bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH_X86_64, 0, 3),
+            bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+            bpf_jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, libc::SYS_brk as u32, 0, 1),
+            bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_TRAP),
+            bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+        ];
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+
+        // installing a filter without CAP_SYS_ADMIN requires no_new_privs
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+            || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog as *const libc::sock_fprog) != 0 {
+            return Err(BrkError::Install(std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt, jf, k }
+}
+
+/// returns the region that serves brk() calls made from the given instruction pointer
+fn region_for(ip: usize) -> Option<&'static BrkRegion> {
+    let mut taken = REGIONS.iter().filter(|region| region.taken.load(Ordering::SeqCst));
+    taken.clone()
+        .find(|region| ip >= region.owner_start.load(Ordering::SeqCst) && ip < region.owner_end.load(Ordering::SeqCst))
+        .or_else(|| taken.next())
+}
+
+/// emulates brk() on a region: the break can be moved anywhere within the region, requests outside of it
+/// fail by returning the current break, just like the kernel does
+fn emulate_brk(region: &BrkRegion, requested: usize) -> usize {
+    let current = region.current.load(Ordering::SeqCst);
+    if requested < region.start.load(Ordering::SeqCst) || requested > region.end.load(Ordering::SeqCst) {
+        return current;
+    }
+
+    let old_end = (current + PAGE_SIZE - 1) & PAGE_MASK;
+    let new_end = (requested + PAGE_SIZE - 1) & PAGE_MASK;
+    unsafe {
+        if new_end > old_end {
+            // make the pages the break grows into accessible
+            if libc::mprotect(old_end as *mut c_void, new_end - old_end, BRK_PROT.load(Ordering::SeqCst)) != 0 {
+                return current;
+            }
+        } else if new_end < old_end {
+            // replace the released pages with fresh inaccessible ones, so that they are zero once the break grows again
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED;
+            if libc::mmap(new_end as *mut c_void, old_end - new_end, libc::PROT_NONE, flags, -1, 0) == libc::MAP_FAILED {
+                return current;
+            }
+        }
+    }
+
+    region.current.store(requested, Ordering::SeqCst);
+    requested
+}
+
+/// SIGSYS handler that serves the brk() calls trapped by the seccomp filter
+extern "C" fn handle_sigsys(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    unsafe {
+        let syscall = *((info as *const u8).add(SIGINFO_SYSCALL) as *const i32);
+        if (*info).si_code != SYS_SECCOMP || syscall as libc::c_long != libc::SYS_brk {
+            // this SIGSYS was not raised by our filter, so perform the default action instead
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+            return;
+        }
+
+        let ucontext = ctx as *mut libc::ucontext_t;
+        let gregs = &mut (*ucontext).uc_mcontext.gregs;
+        let ip = gregs[libc::REG_RIP as usize] as usize;
+        let requested = gregs[libc::REG_RDI as usize] as usize;
+
+        gregs[libc::REG_RAX as usize] = match region_for(ip) {
+            Some(region) => emulate_brk(region, requested) as i64,
+            None => -libc::ENOSYS as i64,
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(load_addr: usize) -> ElfLoad {
+        ElfLoad {
+            load_addr,
+            load_bias: load_addr,
+            load_end: load_addr + PAGE_SIZE,
+            entry: load_addr,
+            phdr_addr: load_addr,
+            ranges: Vec::new(),
+            segments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn releases_slots_for_reuse() {
+        let mut reservations: Vec<BrkReservation> = (0..MAX_REGIONS)
+            .map(|idx| reserve(&image(0x10000 * (idx + 1)), 4 * PAGE_SIZE, false).unwrap())
+            .collect();
+        assert!(matches!(reserve(&image(0), 4 * PAGE_SIZE, false), Err(BrkError::TooManyImages)));
+
+        // brk() calls are served by the region of the calling image, or by the program's region
+        let owner = region_for(0x20000 + 0x10).unwrap();
+        assert_eq!(owner.owner_start.load(Ordering::SeqCst), 0x20000);
+        let start = owner.start.load(Ordering::SeqCst);
+        assert_eq!(emulate_brk(owner, start + 0x10), start + 0x10);
+        assert_eq!(emulate_brk(owner, start + 8 * PAGE_SIZE), start + 0x10);
+        assert_eq!(region_for(0x1000).unwrap().owner_start.load(Ordering::SeqCst), 0x10000);
+
+        let released = reservations.remove(3);
+        drop(released);
+        let reservation = reserve(&image(0x90000), 4 * PAGE_SIZE, false).unwrap();
+        assert_eq!(reservation.idx, 3);
+
+        drop(reservation);
+        reservations.clear();
+        assert!(region_for(0x10000).is_none());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::parse_elf::{
+    ElfError,
+    LoadInfo,
+    PT_DYNAMIC
+};
+
+/// standard size of an Elf64_Dyn entry
+const SIZE_OF_DYN: usize = 16;
+
+/// dynamic entry tags (d_tag)
+pub const DT_NULL: u64 = 0;
+pub const DT_NEEDED: u64 = 1;
+pub const DT_PLTRELSZ: u64 = 2;
+pub const DT_PLTGOT: u64 = 3;
+pub const DT_HASH: u64 = 4;
+pub const DT_STRTAB: u64 = 5;
+pub const DT_SYMTAB: u64 = 6;
+pub const DT_RELA: u64 = 7;
+pub const DT_RELASZ: u64 = 8;
+pub const DT_RELAENT: u64 = 9;
+pub const DT_STRSZ: u64 = 10;
+pub const DT_SYMENT: u64 = 11;
+pub const DT_INIT: u64 = 12;
+pub const DT_FINI: u64 = 13;
+pub const DT_SONAME: u64 = 14;
+pub const DT_RPATH: u64 = 15;
+pub const DT_SYMBOLIC: u64 = 16;
+pub const DT_REL: u64 = 17;
+pub const DT_RELSZ: u64 = 18;
+pub const DT_RELENT: u64 = 19;
+pub const DT_PLTREL: u64 = 20;
+pub const DT_DEBUG: u64 = 21;
+pub const DT_TEXTREL: u64 = 22;
+pub const DT_JMPREL: u64 = 23;
+pub const DT_BIND_NOW: u64 = 24;
+pub const DT_INIT_ARRAY: u64 = 25;
+pub const DT_FINI_ARRAY: u64 = 26;
+pub const DT_INIT_ARRAYSZ: u64 = 27;
+pub const DT_FINI_ARRAYSZ: u64 = 28;
+pub const DT_RUNPATH: u64 = 29;
+pub const DT_FLAGS: u64 = 30;
+pub const DT_PREINIT_ARRAY: u64 = 32;
+pub const DT_PREINIT_ARRAYSZ: u64 = 33;
+pub const DT_RELRSZ: u64 = 35;
+pub const DT_RELR: u64 = 36;
+pub const DT_RELRENT: u64 = 37;
+pub const DT_GNU_HASH: u64 = 0x6ffffef5;
+pub const DT_VERSYM: u64 = 0x6ffffff0;
+pub const DT_RELACOUNT: u64 = 0x6ffffff9;
+pub const DT_RELCOUNT: u64 = 0x6ffffffa;
+pub const DT_FLAGS_1: u64 = 0x6ffffffb;
+pub const DT_VERDEF: u64 = 0x6ffffffc;
+pub const DT_VERDEFNUM: u64 = 0x6ffffffd;
+pub const DT_VERNEED: u64 = 0x6ffffffe;
+pub const DT_VERNEEDNUM: u64 = 0x6fffffff;
+
+/// values for DT_FLAGS
+pub const DF_ORIGIN: u64 = 0x1;
+pub const DF_SYMBOLIC: u64 = 0x2;
+pub const DF_TEXTREL: u64 = 0x4;
+pub const DF_BIND_NOW: u64 = 0x8;
+pub const DF_STATIC_TLS: u64 = 0x10;
+
+/// values for DT_FLAGS_1
+pub const DF_1_NOW: u64 = 0x1;
+pub const DF_1_NODELETE: u64 = 0x8;
+pub const DF_1_NOOPEN: u64 = 0x40;
+pub const DF_1_ORIGIN: u64 = 0x80;
+pub const DF_1_PIE: u64 = 0x08000000;
+
+
+/// Represents an Elf64_Dyn as found in an actual ELF file
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct Elf64Dyn {
+    pub tag:    u64,
+    pub val:    u64
+}
+
+/// an array of addresses, such as DT_INIT_ARRAY, given by its (unrelocated) address and size in bytes
+#[derive(Debug, Copy, Clone)]
+pub struct DynArray {
+    pub addr:   usize,
+    pub size:   usize,
+}
+
+/// a relocation table, such as DT_RELA, given by its (unrelocated) address, size in bytes and entry size
+#[derive(Debug, Copy, Clone)]
+pub struct RelocTable {
+    pub addr:       usize,
+    pub size:       usize,
+    pub entsize:    usize,
+}
+
+/// the kind of relocations used by the PLT (DT_PLTREL)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PltRelType {
+    Rel,
+    Rela,
+}
+
+/// The decoded PT_DYNAMIC segment of an ELF. All addresses are the virtual addresses found in the
+/// file and still need to be rebased through the load bias of the image for ET_DYN files
+#[derive(Debug, Clone)]
+pub struct DynamicInfo {
+    /// DT_NEEDED libraries, in the order in which they are listed
+    pub needed:         Vec<String>,
+    pub soname:         Option<String>,
+    /// DT_RPATH and DT_RUNPATH, split at ':'
+    pub rpath:          Vec<String>,
+    pub runpath:        Vec<String>,
+
+    pub init:           Option<usize>,
+    pub fini:           Option<usize>,
+    pub preinit_array:  Option<DynArray>,
+    pub init_array:     Option<DynArray>,
+    pub fini_array:     Option<DynArray>,
+
+    pub rela:           Option<RelocTable>,
+    pub rel:            Option<RelocTable>,
+    pub relr:           Option<RelocTable>,
+    /// the PLT relocations (DT_JMPREL) and their type (DT_PLTREL)
+    pub jmprel:         Option<DynArray>,
+    pub pltrel:         Option<PltRelType>,
+    pub pltgot:         Option<usize>,
+
+    pub hash:           Option<usize>,
+    pub gnu_hash:       Option<usize>,
+    pub symtab:         Option<usize>,
+    pub strtab:         Option<usize>,
+
+    /// DT_FLAGS and DT_FLAGS_1, 0 if not present
+    pub flags:          u64,
+    pub flags_1:        u64,
+
+    /// every entry up to DT_NULL, for tags that are not decoded above
+    pub entries:        Vec<Elf64Dyn>,
+}
+
+impl DynamicInfo {
+
+    /// Decodes the PT_DYNAMIC segment of an ELF. Returns None for files that are not dynamically linked
+    pub fn parse(load_info: &LoadInfo) -> Result<Option<Self>, ElfError> {
+        let phdr = match load_info.program_header(PT_DYNAMIC) {
+            Some(phdr) => phdr,
+            None => return Ok(None),
+        };
+
+        let offset = phdr.offset as usize;
+        let data = offset.checked_add(phdr.filesz as usize)
+            .and_then(|end| load_info.data.get(offset..end))
+            .ok_or(ElfError::BadDynamic)?;
+
+        // read all entries up to the terminating DT_NULL entry
+        let mut entries: Vec<Elf64Dyn> = Vec::new();
+        for entry in data.chunks_exact(SIZE_OF_DYN) {
+            let mut buffer_clone: [u8; SIZE_OF_DYN] = [0; SIZE_OF_DYN];
+            buffer_clone.copy_from_slice(entry);
+
+            let dyn_entry = unsafe {
+                std::mem::transmute::<[u8; SIZE_OF_DYN], Elf64Dyn>(buffer_clone)
+            };
+
+            if dyn_entry.tag == DT_NULL {
+                break;
+            }
+            entries.push(dyn_entry);
+        }
+
+        if entries.len() == data.len() / SIZE_OF_DYN {
+            return Err(ElfError::BadDynamic);
+        }
+
+        // flags and types are used as they are, addresses and sizes as usize
+        let raw = |tag: u64| entries.iter().find(|entry| entry.tag == tag).map(|entry| entry.val);
+        let value = |tag: u64| raw(tag).map(|val| val as usize);
+        let array = |addr_tag: u64, size_tag: u64| {
+            value(addr_tag).map(|addr| DynArray { addr, size: value(size_tag).unwrap_or(0) })
+        };
+        let table = |addr_tag: u64, size_tag: u64, ent_tag: u64| {
+            value(addr_tag).map(|addr| RelocTable { addr, size: value(size_tag).unwrap_or(0), entsize: value(ent_tag).unwrap_or(0) })
+        };
+
+        // the string table is referenced through its virtual address, so translate it into a file offset
+        let strtab = value(DT_STRTAB);
+        let strings = match strtab {
+            Some(strtab) => {
+                let strsz = value(DT_STRSZ).unwrap_or(0);
+                let start = load_info.vaddr_to_offset(strtab).ok_or(ElfError::BadDynamic)?;
+                Some(start.checked_add(strsz)
+                    .and_then(|end| load_info.data.get(start..end))
+                    .ok_or(ElfError::BadDynamic)?)
+            },
+            None => None,
+        };
+
+        let string = |offset: u64| -> Result<String, ElfError> {
+            strings
+                .and_then(|strings| strings.get(offset as usize..))
+                .and_then(|name| name.iter().position(|&c| c == 0).map(|len| &name[..len]))
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .ok_or(ElfError::BadDynamicString(offset))
+        };
+
+        let mut needed = Vec::new();
+        let mut soname = None;
+        let mut rpath = Vec::new();
+        let mut runpath = Vec::new();
+        for entry in entries.iter() {
+            match entry.tag {
+                DT_NEEDED => needed.push(string(entry.val)?),
+                DT_SONAME => soname = Some(string(entry.val)?),
+                DT_RPATH => rpath.extend(string(entry.val)?.split(':').map(String::from)),
+                DT_RUNPATH => runpath.extend(string(entry.val)?.split(':').map(String::from)),
+                _ => {}
+            }
+        }
+
+        Ok(Some(DynamicInfo {
+            needed,
+            soname,
+            rpath,
+            runpath,
+            init: value(DT_INIT),
+            fini: value(DT_FINI),
+            preinit_array: array(DT_PREINIT_ARRAY, DT_PREINIT_ARRAYSZ),
+            init_array: array(DT_INIT_ARRAY, DT_INIT_ARRAYSZ),
+            fini_array: array(DT_FINI_ARRAY, DT_FINI_ARRAYSZ),
+            rela: table(DT_RELA, DT_RELASZ, DT_RELAENT),
+            rel: table(DT_REL, DT_RELSZ, DT_RELENT),
+            relr: table(DT_RELR, DT_RELRSZ, DT_RELRENT),
+            jmprel: array(DT_JMPREL, DT_PLTRELSZ),
+            pltrel: raw(DT_PLTREL).and_then(|pltrel| match pltrel {
+                DT_REL => Some(PltRelType::Rel),
+                DT_RELA => Some(PltRelType::Rela),
+                _ => None,
+            }),
+            pltgot: value(DT_PLTGOT),
+            hash: value(DT_HASH),
+            gnu_hash: value(DT_GNU_HASH),
+            symtab: value(DT_SYMTAB),
+            strtab,
+            flags: raw(DT_FLAGS).unwrap_or(0),
+            flags_1: raw(DT_FLAGS_1).unwrap_or(0),
+            entries,
+        }))
+    }
+
+    /// returns the value of the first entry with the given tag
+    pub fn value(&self, tag: u64) -> Option<u64> {
+        self.entries.iter().find(|entry| entry.tag == tag).map(|entry| entry.val)
+    }
+
+    /// returns true if all symbols are bound at load time (DT_BIND_NOW, DF_BIND_NOW or DF_1_NOW)
+    pub fn bind_now(&self) -> bool {
+        self.value(DT_BIND_NOW).is_some() || (self.flags & DF_BIND_NOW) != 0 || (self.flags_1 & DF_1_NOW) != 0
+    }
+
+    /// returns true if the image is a position independent executable (DF_1_PIE)
+    pub fn is_pie(&self) -> bool {
+        (self.flags_1 & DF_1_PIE) != 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_elf::{parse_elf_bytes, Elf64Phdr};
+    use crate::parse_elf::tests::{elf_image, load_segment, put, BASE};
+
+    /// where the dynamic section and its string table are placed in the image
+    const DYNAMIC_OFF: usize = 0x300;
+    const DYNSTR_OFF: usize = 0x400;
+
+    const DYNSTR: &[u8] = b"\0libc.so.6\0libfoo.so\0/a:/b\0";
+
+    /// builds an image whose PT_DYNAMIC segment holds the given entries, followed by as many DT_NULL entries as fit
+    fn dynamic_image(entries: &[(u64, u64)], size: usize) -> Vec<u8> {
+        let dynamic = Elf64Phdr {
+            ptype: PT_DYNAMIC,
+            pflags: 6,
+            offset: DYNAMIC_OFF as u64,
+            vaddr: BASE + DYNAMIC_OFF as u64,
+            paddr: BASE + DYNAMIC_OFF as u64,
+            filesz: size as u64,
+            memsz: size as u64,
+            align: 8,
+        };
+        let mut image = elf_image(&[load_segment(), dynamic]);
+
+        for (idx, (tag, val)) in entries.iter().enumerate() {
+            put(&mut image, DYNAMIC_OFF + idx * SIZE_OF_DYN, &tag.to_le_bytes());
+            put(&mut image, DYNAMIC_OFF + idx * SIZE_OF_DYN + 8, &val.to_le_bytes());
+        }
+        put(&mut image, DYNSTR_OFF, DYNSTR);
+
+        image
+    }
+
+    fn parse(image: &[u8]) -> Result<Option<DynamicInfo>, ElfError> {
+        DynamicInfo::parse(&parse_elf_bytes(image).unwrap())
+    }
+
+    #[test]
+    fn decodes_entries() {
+        let entries = [
+            (DT_NEEDED, 1),
+            (DT_NEEDED, 11),
+            (DT_RUNPATH, 21),
+            (DT_STRTAB, BASE + DYNSTR_OFF as u64),
+            (DT_STRSZ, DYNSTR.len() as u64),
+            (DT_INIT_ARRAY, 0x401000),
+            (DT_INIT_ARRAYSZ, 0x10),
+            (DT_RELA, 0x402000),
+            (DT_RELASZ, 0x30),
+            (DT_RELAENT, 0x18),
+            (DT_PLTREL, DT_RELA),
+            (DT_FLAGS_1, DF_1_PIE | DF_1_NOW),
+        ];
+        let info = parse(&dynamic_image(&entries, 0x100)).unwrap().unwrap();
+
+        assert_eq!(info.needed, ["libc.so.6", "libfoo.so"]);
+        assert_eq!(info.runpath, ["/a", "/b"]);
+        assert!(info.soname.is_none() && info.rpath.is_empty());
+        assert_eq!(info.strtab, Some(BASE as usize + DYNSTR_OFF));
+        assert_eq!(info.init_array.map(|array| (array.addr, array.size)), Some((0x401000, 0x10)));
+        assert_eq!(info.rela.map(|table| (table.addr, table.size, table.entsize)), Some((0x402000, 0x30, 0x18)));
+        assert_eq!(info.pltrel, Some(PltRelType::Rela));
+        assert!(info.is_pie() && info.bind_now());
+        assert_eq!(info.entries.len(), entries.len());
+    }
+
+    #[test]
+    fn static_images_have_no_dynamic_info() {
+        assert!(DynamicInfo::parse(&parse_elf_bytes(&elf_image(&[load_segment()])).unwrap()).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_missing_dt_null() {
+        let entries = [(DT_FLAGS, DF_BIND_NOW), (DT_FLAGS_1, DF_1_NOW)];
+        assert!(matches!(parse(&dynamic_image(&entries, 2 * SIZE_OF_DYN)), Err(ElfError::BadDynamic)));
+    }
+
+    #[test]
+    fn rejects_strings_outside_of_dt_strtab() {
+        let entries = [
+            (DT_NEEDED, 0x100),
+            (DT_STRTAB, BASE + DYNSTR_OFF as u64),
+            (DT_STRSZ, DYNSTR.len() as u64),
+        ];
+        assert!(matches!(parse(&dynamic_image(&entries, 0x100)), Err(ElfError::BadDynamicString(0x100))));
+    }
+}
@@ -0,0 +1,164 @@
+extern crate libc;
+use std::arch::asm;
+use std::io;
+
+/// the highest signal number on x86-64 (SIGRTMAX of the kernel)
+const NSIG: libc::c_int = 64;
+
+/// the signature glibc registers its rseq area with on x86-64
+const RSEQ_SIG: u32 = 0x53053053;
+const RSEQ_FLAG_UNREGISTER: libc::c_int = 1;
+
+/// the size of struct rseq, which glibc registers even if it reports a smaller feature size
+const RSEQ_AREA_SIZE: u32 = 32;
+
+/// struct sigaction as the rt_sigaction() syscall expects it. The sigaction() of glibc refuses to touch the
+/// signals it reserves for itself, so the syscall is used directly
+#[repr(C)]
+struct KernelSigaction {
+    handler: libc::sighandler_t,
+    flags: libc::c_ulong,
+    restorer: libc::sighandler_t,
+    mask: u64,
+}
+
+
+/// Puts the process into the state execve() leaves a new program in, so that nothing the loader or the Rust
+/// runtime set up leaks into the loaded program:
+///
+/// * signals with a handler are reset to SIG_DFL, ignored ones stay ignored. SIGPIPE is the exception, as the
+///   Rust runtime ignores it on its own
+/// * no signal is blocked and the alternate signal stack is disabled
+/// * every file descriptor that is marked O_CLOEXEC is closed
+/// * the rseq area of the loader's libc is unregistered, so that the loaded libc can register its own
+///
+/// This has to be one of the last things the loader does before jumping to the entry point
+pub fn reset() -> Result<(), io::Error> {
+    reset_signal_handlers()?;
+    reset_signal_mask()?;
+    disable_sigaltstack()?;
+    close_cloexec_fds();
+    unregister_rseq();
+
+    Ok(())
+}
+
+/// unregisters the rseq area glibc registered for the main thread, if any. The kernel writes to that area on every
+/// preemption, so it must not outlive the memory of the loader. glibc publishes where the area is relative to the
+/// thread pointer through __rseq_offset and __rseq_size, which are looked up at runtime, as other libcs lack them
+pub fn unregister_rseq() {
+    unsafe {
+        let offset = libc::dlsym(std::ptr::null_mut(), "__rseq_offset\0".as_ptr() as *const libc::c_char) as *const isize;
+        let size = libc::dlsym(std::ptr::null_mut(), "__rseq_size\0".as_ptr() as *const libc::c_char) as *const u32;
+        if offset.is_null() || size.is_null() || *size == 0 {
+            return;
+        }
+
+        // the TCB of glibc starts with a pointer to itself
+        let thread_pointer: usize;
+        asm!("mov {}, fs:0", out(reg) thread_pointer);
+        let area = (thread_pointer as isize + *offset) as usize;
+
+        // the length has to match the registered one exactly
+        for len in [RSEQ_AREA_SIZE, *size] {
+            if libc::syscall(libc::SYS_rseq, area, len, RSEQ_FLAG_UNREGISTER, RSEQ_SIG) == 0 {
+                return;
+            }
+        }
+    }
+}
+
+fn rt_sigaction(sig: libc::c_int, act: Option<&KernelSigaction>, old: Option<&mut KernelSigaction>) -> Result<(), io::Error> {
+    let act = act.map_or(std::ptr::null(), |act| act as *const KernelSigaction);
+    let old = old.map_or(std::ptr::null_mut(), |old| old as *mut KernelSigaction);
+    let res = unsafe {
+        libc::syscall(libc::SYS_rt_sigaction, sig, act, old, std::mem::size_of::<u64>())
+    };
+
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// resets every signal with a handler to SIG_DFL, just like flush_signal_handlers() does during execve()
+fn reset_signal_handlers() -> Result<(), io::Error> {
+    for sig in 1..=NSIG {
+        // the actions of these two can not be changed
+        if sig == libc::SIGKILL || sig == libc::SIGSTOP {
+            continue;
+        }
+
+        let mut old = KernelSigaction { handler: 0, flags: 0, restorer: 0, mask: 0 };
+        rt_sigaction(sig, None, Some(&mut old))?;
+
+        if old.handler == libc::SIG_DFL || (old.handler == libc::SIG_IGN && sig != libc::SIGPIPE) {
+            continue;
+        }
+
+        let default = KernelSigaction { handler: libc::SIG_DFL, flags: 0, restorer: 0, mask: 0 };
+        rt_sigaction(sig, Some(&default), None)?;
+    }
+
+    Ok(())
+}
+
+/// unblocks every signal
+fn reset_signal_mask() -> Result<(), io::Error> {
+    let mask: u64 = 0;
+    let res = unsafe {
+        libc::syscall(libc::SYS_rt_sigprocmask, libc::SIG_SETMASK, &mask as *const u64, std::ptr::null_mut::<u64>(), std::mem::size_of::<u64>())
+    };
+
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// disables the alternate signal stack the Rust runtime uses to report stack overflows
+fn disable_sigaltstack() -> Result<(), io::Error> {
+    let stack = libc::stack_t {
+        ss_sp: std::ptr::null_mut(),
+        ss_flags: libc::SS_DISABLE,
+        ss_size: 0,
+    };
+
+    let res = unsafe {
+        libc::sigaltstack(&stack, std::ptr::null_mut())
+    };
+
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// closes all file descriptors that are marked O_CLOEXEC. The open ones are listed through /proc/self/fd, if
+/// that is not available every descriptor below RLIMIT_NOFILE is checked instead
+fn close_cloexec_fds() {
+    let fds: Vec<libc::c_int> = match std::fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse().ok()))
+            .collect(),
+        Err(_) => {
+            let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            let res = unsafe {
+                libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit)
+            };
+            let max_fd = if res == 0 { limit.rlim_cur.min(libc::c_int::MAX as u64) as libc::c_int } else { 1024 };
+            (0..max_fd).collect()
+        }
+    };
+
+    // the descriptor read_dir() used is already closed again, checking it is harmless
+    for fd in fds {
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags != -1 && (flags & libc::FD_CLOEXEC) != 0 {
+                libc::close(fd);
+            }
+        }
+    }
+}
@@ -0,0 +1,16 @@
+// The loader as a library: parse an ELF file, map it together with its interpreter and set up the initial stack
+// the kernel would, then jump to it. Loader wraps all of these steps, the modules can also be used on their own
+pub mod auxv;
+pub mod brk;
+pub mod dynamic;
+pub mod exec_reset;
+pub mod load_elf;
+pub mod maps;
+pub mod parse_elf;
+pub mod stack_setup;
+pub mod symbols;
+pub mod ul_exec;
+
+mod loader;
+
+pub use loader::{LoadedProgram, Loader, LoaderError};
@@ -4,27 +4,43 @@ extern crate nix;
 extern crate libc;
 use nix::sys::mman::{
     mmap,
+    munmap,
     mprotect,
     ProtFlags,
     MapFlags
 };
+use nix::errno::Errno;
 use core::ffi::c_void;
+use std::fmt;
+use std::os::unix::io::{AsRawFd, RawFd};
 
+extern crate rand;
+use rand::Rng;
 
-use crate::parse_elf::{Elf64Phdr, LoadInfo, ElfType};
 
-// these values are used to translate ElfPhdr64
-const PF_X: u32 = 1;
-const PF_W: u32 = 2;
-const PF_R: u32 = 4;
+use crate::maps::{self, Mapping};
+use crate::parse_elf::{Elf64Phdr, LoadInfo, ElfType, PF_R, PF_W, PF_X, PT_LOAD, PT_PHDR};
 
+/// the page size of x86-64
+pub(crate) const PAGE_SIZE: usize = 0x1000;
+
+/// the end of the user address space on x86-64, TASK_SIZE of the kernel
+pub(crate) const TASK_SIZE: usize = 0x7ffffffff000;
 
 const ELF_MIN_ALIGNMENT: usize = 0x1000;
 const ELF_MIN_ALIGNMENT_MASK: usize = !(ELF_MIN_ALIGNMENT - 1);
 
+/// MAP_FIXED_NOREPLACE: map at exactly the given address, but fail with EEXIST instead of replacing a mapping
+pub(crate) fn map_fixed_noreplace() -> MapFlags {
+    // nix does not know about MAP_FIXED_NOREPLACE yet
+    unsafe {
+        MapFlags::from_bits_unchecked(libc::MAP_FIXED_NOREPLACE)
+    }
+}
+
 
 
-/// represents a PT_LOAD segment of the ELF to be loaded and holds the data to be loaded
+/// represents a PT_LOAD segment of the ELF to be loaded. The data is mapped from the file at load time
 #[derive(Debug, Clone)]
 pub struct ElfSegment {
     pub virt_addr:  usize,
@@ -32,7 +48,6 @@ pub struct ElfSegment {
     pub offset:     usize,
     filesize:       usize,
     alignment:      usize,
-    data:           Vec<u8>,
     prot:           ProtFlags,
 }
 
@@ -42,13 +57,12 @@ impl ElfSegment {
 
     /// takes a raw Elf64Phdr as parsed by the parsing module and converts
     /// it into a loadable segment
-    pub fn new(hdr: &Elf64Phdr, data: Vec<u8>) -> Self {
+    pub fn new(hdr: &Elf64Phdr) -> Self {
         Self {
             virt_addr: hdr.vaddr as usize,
             memsize: hdr.memsz as usize,
             filesize: hdr.filesz as usize,
             alignment: hdr.align as usize,
-            data: data,
             offset: hdr.offset as usize,
             prot: Self::get_prot_flags_from_progam_flags(hdr.pflags),
         }
@@ -73,30 +87,190 @@ impl ElfSegment {
 }
 
 
+/// ELF_ET_DYN_BASE of x86-64: the kernel loads PIE programs at 2/3 of the 47 bit address space
+const ELF_ET_DYN_BASE: usize = TASK_SIZE / 3 * 2;
+
+/// the number of random bits arch_mmap_rnd() adds to ELF_ET_DYN_BASE (the default of vm.mmap_rnd_bits)
+const MMAP_RND_BITS: usize = 28;
+
+
+/// Everything that can go wrong while mapping an image into memory
+#[derive(Debug)]
+pub enum LoadError {
+    /// a mapping needed for the image could not be created
+    Map { addr: usize, size: usize, err: nix::Error },
+    /// a gap between the segments could not be unmapped
+    Unmap { addr: usize, size: usize, err: nix::Error },
+    /// the address range the image has to be loaded at is already in use
+    AddressInUse { addr: usize, size: usize, collisions: Vec<Mapping> },
+    /// the requested base address is not page aligned
+    UnalignedBase(usize),
+    /// only position independent (ET_DYN) images can be placed at a chosen base address
+    NotRelocatable,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Map { addr, size, err } => write!(f, "failed to map {:#x} bytes at {:#x}: {}", size, addr, err),
+            LoadError::Unmap { addr, size, err } => write!(f, "failed to unmap {:#x} bytes at {:#x}: {}", size, addr, err),
+            LoadError::AddressInUse { addr, size, collisions } => {
+                write!(f, "the range {:#x}-{:#x} the image has to be loaded at is already in use", addr, addr + size)?;
+                if !collisions.is_empty() {
+                    write!(f, ", it collides with:")?;
+                }
+                for mapping in collisions {
+                    write!(f, "\n    {}", mapping)?;
+                }
+                Ok(())
+            },
+            LoadError::UnalignedBase(base) => write!(f, "the base address {:#x} is not page aligned", base),
+            LoadError::NotRelocatable => write!(f, "only position independent (ET_DYN) programs can be loaded at a chosen base address"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+
+/// Decides where a position independent (ET_DYN) image is placed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Placement {
+    /// let mmap() choose a suitable location, honoring the alignment of the segments
+    #[default]
+    Anywhere,
+    /// reproduce the placement of the kernel: programs with an interpreter are loaded at ELF_ET_DYN_BASE plus
+    /// arch_mmap_rnd() entropy, everything else is placed by mmap(). Without randomization the loader itself
+    /// usually occupies ELF_ET_DYN_BASE, mmap() places the program in that case as well
+    Kernel,
+    /// load the first segment of the image at the given, page aligned address
+    Base(usize),
+}
+
+/// Process wide settings that influence how images are mapped
+#[derive(Debug, Clone, Default)]
+pub struct LoadConfig {
+    /// emulate the READ_IMPLIES_EXEC personality: every readable mapping is also executable
+    pub read_implies_exec: bool,
+    /// where ET_DYN images are placed
+    pub placement: Placement,
+}
+
+/// The pages a single PT_LOAD segment ended up in
+#[derive(Debug, Clone)]
+pub struct MappedSegment {
+    /// the first page of the segment
+    pub start: usize,
+    /// the page aligned end of the segment
+    pub end: usize,
+    /// the end of the pages that are mapped from the file, the rest up to end is backed by fresh zero pages
+    pub file_end: usize,
+    /// the file offset of the first page
+    pub offset: usize,
+    pub prot: ProtFlags,
+}
+
+impl fmt::Display for MappedSegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}-{:x} {}{}{} offset {:#x}, {:#x} bytes from the file, {:#x} zero bytes",
+            self.start, self.end,
+            if self.prot.contains(ProtFlags::PROT_READ) { 'r' } else { '-' },
+            if self.prot.contains(ProtFlags::PROT_WRITE) { 'w' } else { '-' },
+            if self.prot.contains(ProtFlags::PROT_EXEC) { 'x' } else { '-' },
+            self.offset, self.file_end - self.start, self.end - self.file_end)
+    }
+}
+
+/// The address range reserved for an image. Unless the image is loaded completely, the range is unmapped again
+/// together with every segment that was already mapped into it
+struct Reservation {
+    addr: usize,
+    size: usize,
+}
+
+impl Reservation {
+    /// hands the range over to the loaded image
+    fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.addr as *mut c_void, self.size);
+        }
+    }
+}
+
 /// Statefully emulates the Linux kernel ELF loading logic
 pub struct ElfLoad {
     pub load_addr: usize,
+    /// the value that is added to the virtual addresses found in the ELF to obtain runtime addresses.
+    /// It is 0 for ET_EXEC images, as their addresses are absolute
+    pub load_bias: usize,
+    /// the page aligned end of the last segment, which is where the kernel places the program break
+    pub load_end: usize,
+    /// the runtime address of the entry point
+    pub entry: usize,
+    /// the runtime address of the program headers, passed as AT_PHDR
+    pub phdr_addr: usize,
+    /// every address range the image occupies, the copy of the program headers included
+    pub ranges: Vec<(usize, usize)>,
+    /// where each PT_LOAD segment was mapped and with which protection
+    pub segments: Vec<MappedSegment>,
 }
 
 impl ElfLoad {
-    fn get_total_mapping_size(segments: &Vec<ElfSegment>) -> usize {
+    /// returns the size of the span covered by all segments. The parser guarantees that there is at
+    /// least one segment and that the segments are sorted and do not overlap
+    fn get_total_mapping_size(segments: &[ElfSegment]) -> usize {
         let last_idx = segments.len() - 1;
 
         // logic from the linux kernel
-        segments[last_idx].virt_addr + segments[last_idx].memsize - (segments[0].virt_addr & !15)
+        segments[last_idx].virt_addr + segments[last_idx].memsize - (segments[0].virt_addr & ELF_MIN_ALIGNMENT_MASK)
+    }
+
+    /// returns the largest power of two alignment of all segments, but at least the page size. This is
+    /// maximum_alignment() of the kernel
+    fn get_maximum_alignment(segments: &[ElfSegment]) -> usize {
+        segments.iter()
+            .map(|seg| seg.alignment)
+            .filter(|align| align.is_power_of_two())
+            .fold(ELF_MIN_ALIGNMENT, usize::max)
+    }
+
+    /// returns true if the kernel would randomize the address space of this process (PF_RANDOMIZE)
+    pub fn randomization_enabled() -> bool {
+        let persona = unsafe {
+            libc::personality(0xffffffff)
+        };
+        if persona != -1 && (persona & libc::ADDR_NO_RANDOMIZE) != 0 {
+            return false;
+        }
+
+        std::fs::read_to_string("/proc/sys/kernel/randomize_va_space")
+            .map(|value| value.trim() != "0")
+            .unwrap_or(true)
     }
 
-    pub fn load(load_info: &LoadInfo) -> Self {
+    /// the equivalent of arch_mmap_rnd() on x86-64: a random, page aligned offset
+    fn arch_mmap_rnd() -> usize {
+        let rnd: usize = rand::thread_rng().gen();
+        (rnd & ((1 << MMAP_RND_BITS) - 1)) << 12
+    }
+
+    pub fn load(load_info: &LoadInfo, config: &LoadConfig) -> Result<Self, LoadError> {
         /* There are two types of ELF files:
         *  ET_EXEC and ET_DYN. ET_EXEC are position dependent and are given a load address by the compiler (for gcc it is usually 0x40000)
         *  In the case of such an executable, simply obtain the virtual address of the first PT_LOAD program header and use it as 
         *  an address for a MAP_FIXED mmap() mapping. 
 
         *  In case of an ET_DYN ELF, the code is position independent and can be loaded anywhere. The Linux kernel usually chooses
-        *  0x555555554aaa + ASLR offset. However, since we are already loaded at that address we just let mmap() chose a suitable location
+        *  0x555555554aaa + ASLR offset. Since we are already loaded around that address, by default we just let mmap() chose a suitable location
         *  for the new binary. This can be a little awkward as mmap() chooses an address in the 0x7fff... range and might map the file next
         *  to another file. This is awkward because the libc heap uses brk() (the end of the loaded program) to initialize the heap. 
-        *  Therefor, we need to set brk() to a safe location where the heap can grow.
+        *  Therefor, Placement::Kernel reproduces the placement of the kernel and Placement::Base loads the image at a chosen address.
 
         The corresponding Linux kernel code of the following logic is:
 
@@ -107,10 +281,12 @@ impl ElfLoad {
 				alignment = maximum_alignment(elf_phdata, elf_ex->e_phnum);
 				if (alignment)
 					load_bias &= ~(alignment - 1);
-                elf_flags |= MAP_FIXED;
+                elf_flags |= MAP_FIXED_NOREPLACE;
             else
                 load_bias = 0
 
+            load_bias = ELF_PAGESTART(load_bias - vaddr);
+
 
             The kernel then loads each segment through load_bias + vaddr of section. This way, 
             both static binaries (load bias of 0) that have a set load address and PIE binaries can be loaded with 
@@ -118,69 +294,335 @@ impl ElfLoad {
         */
 
         let segments = &load_info.segments;
+        let first_page = segments[0].virt_addr & ELF_MIN_ALIGNMENT_MASK;
+        let alignment = Self::get_maximum_alignment(segments);
+
+        // figure out where the first page of the image has to go, None lets mmap() decide
+        let fixed_addr = match (&load_info.etype, config.placement) {
+            (ElfType::ElfExec, Placement::Base(_)) => return Err(LoadError::NotRelocatable),
+            (ElfType::ElfExec, _) => Some(first_page),
+            (ElfType::ElfDyn, Placement::Base(base)) => {
+                if (base & (ELF_MIN_ALIGNMENT - 1)) != 0 {
+                    return Err(LoadError::UnalignedBase(base));
+                }
+                Some(base)
+            },
+            (ElfType::ElfDyn, Placement::Kernel) if load_info.elf_interp.is_some() => {
+                let mut load_bias = ELF_ET_DYN_BASE;
+                if Self::randomization_enabled() {
+                    load_bias += Self::arch_mmap_rnd();
+                }
+                load_bias &= !(alignment - 1);
+                Some(load_bias & ELF_MIN_ALIGNMENT_MASK)
+            },
+            (ElfType::ElfDyn, _) => None,
+        };
+
+        // reserve an inaccessible area large enough for the entire ELF binary, so that the segments
+        // can be mapped into it with MAP_FIXED without clobbering anything else. Images at a fixed address, ET_EXEC
+        // ones included, must not replace anything either: just like the kernel, MAP_FIXED_NOREPLACE refuses to
+        // map over the loader itself, its heap or the interpreter
+        let total_mapping_size = Self::get_total_mapping_size(segments);
+        let kernel_placement = load_info.etype == ElfType::ElfDyn && config.placement == Placement::Kernel;
+        let load_addr = match fixed_addr {
+            // the loader is a PIE program itself, so it was placed at ELF_ET_DYN_BASE as well. Without randomization
+            // the program would land right on top of it, in which case it is placed like without a placement
+            Some(addr) => match Self::reserve_noreplace(addr, total_mapping_size) {
+                Err(LoadError::AddressInUse { .. }) if kernel_placement => Self::reserve_aligned(total_mapping_size, alignment)?,
+                res => res?,
+            },
+            None => Self::reserve_aligned(total_mapping_size, alignment)?,
+        };
+        let reservation = Reservation { addr: load_addr, size: total_mapping_size };
+
+        // the load bias translates the virtual addresses of the segments to where they are actually mapped. For
+        // ET_EXEC images this is 0, since the virtual addresses in the headers are absolute
+        let load_base = load_addr - first_page;
+
+        // map the pages of each segment straight from the file, just like the kernel's elf_map() does.
+        // This way the pages are shared with the page cache and only copied once they are written to
+        let fd = load_info.file.as_raw_fd();
+        let mut mapped_segments = Vec::with_capacity(segments.len());
+        for seg in segments.iter() {
+
+            // legacy binaries run with READ_IMPLIES_EXEC, in which case the kernel makes every readable
+            // mapping executable as well
+            let mut prot = seg.prot;
+            if config.read_implies_exec && prot.contains(ProtFlags::PROT_READ) {
+                prot.insert(ProtFlags::PROT_EXEC);
+            }
+
+            mapped_segments.push(Self::map_segment(seg, load_base, prot, fd)?);
+        }
+
+        // whatever is left of the reservation are the gaps between the segments. Just like the kernel, unmap
+        // them, so that stray accesses into them fault instead of silently succeeding
+        Self::unmap_gaps(segments, load_base, load_addr, total_mapping_size)?;
+
+        let phdr_addr = Self::map_program_headers(load_info, load_base)?;
+        reservation.keep();
+
+        let last = &segments[segments.len() - 1];
+        let load_end = (load_base + last.virt_addr + last.memsize + ELF_MIN_ALIGNMENT - 1) & ELF_MIN_ALIGNMENT_MASK;
+
+        // if the program headers had to be copied, their mapping belongs to the image as well
+        let mut ranges = vec![(load_addr, load_end)];
+        if phdr_addr < load_addr || phdr_addr >= load_end {
+            ranges.push((phdr_addr, phdr_addr + load_info.pheader_num * std::mem::size_of::<Elf64Phdr>()));
+        }
+
+        Ok(ElfLoad {
+            load_addr,
+            load_bias: load_base,
+            load_end,
+            entry: load_base + load_info.entry_point,
+            phdr_addr,
+            ranges,
+            segments: mapped_segments,
+        })
+    }
 
+    /// returns the runtime address of the program headers. PT_PHDR tells where they are if the image has it,
+    /// otherwise the file offset of the headers is translated through the PT_LOAD segment that contains them,
+    /// just like the kernel does. If no segment maps the headers, they are copied into a mapping of their own,
+    /// so that libc still finds them, e.g. for dl_iterate_phdr()
+    fn map_program_headers(load_info: &LoadInfo, load_base: usize) -> Result<usize, LoadError> {
+        let phdrs_size = load_info.pheader_num * std::mem::size_of::<Elf64Phdr>();
+        let loads = || load_info.program_headers.iter().filter(|phdr| phdr.ptype == PT_LOAD);
+
+        // PT_PHDR is only trusted if a segment actually maps the headers at that address. Its p_vaddr is not
+        // validated by the parser, headers that would wrap around are simply not covered by any segment
+        if let Some(phdr) = load_info.program_header(PT_PHDR) {
+            let vaddr = phdr.vaddr as usize;
+            if let Some(end) = vaddr.checked_add(phdrs_size) {
+                if loads().any(|seg| vaddr >= seg.vaddr as usize && end <= (seg.vaddr + seg.memsz) as usize) {
+                    return Ok(load_base + vaddr);
+                }
+            }
+        }
+
+        let phoff = load_info.pheader_off;
+        if let Some(seg) = loads().find(|seg| phoff >= seg.offset as usize && phoff + phdrs_size <= (seg.offset + seg.filesz) as usize) {
+            return Ok(load_base + seg.vaddr as usize + (phoff - seg.offset as usize));
+        }
+
+        // no segment maps the headers, place a read only copy of them somewhere
         let mut map_flags = MapFlags::empty();
         map_flags.insert(MapFlags::MAP_PRIVATE);
         map_flags.insert(MapFlags::MAP_ANONYMOUS);
 
-        // if the ELF is static, set the MAP_FIXED flag and set the load address to virtual address of the first segment
-        // otherwise load at any location (address 0 without MAP_FIXED)
-        let mmap_addr = match load_info.etype {
-            ElfType::ElfExec => {
-                map_flags.insert(MapFlags::MAP_FIXED);
-                segments[0].virt_addr - segments[0].offset
-            },
-            ElfType::ElfDyn => 0
-        };
+        let headers = &load_info.data[phoff..phoff + phdrs_size];
+        unsafe {
+            let mapping = mmap(std::ptr::null_mut(), phdrs_size, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, map_flags, -1, 0)
+                .map_err(|err| LoadError::Map { addr: 0, size: phdrs_size, err })?;
+            libc::memcpy(mapping, headers.as_ptr() as *const c_void, phdrs_size);
+            mprotect(mapping, phdrs_size, ProtFlags::PROT_READ)
+                .map_err(|err| LoadError::Map { addr: mapping as usize, size: phdrs_size, err })?;
 
-        let map_flags = map_flags;
+            Ok(mapping as usize)
+        }
+    }
 
-        // make an allocation large enough for the entire ELF binary, make it read and writable 
-        // and populate it with the content. Then change the protection flags for each segment accordingly.
-        let total_mapping_size = Self::get_total_mapping_size(&segments);  
-        let mut prot_flags = ProtFlags::empty();
-        prot_flags.insert(ProtFlags::PROT_READ);
-        prot_flags.insert(ProtFlags::PROT_WRITE);
-        
-        let load_addr = unsafe {
-            mmap(mmap_addr as *mut c_void, total_mapping_size, prot_flags, map_flags, -1, 0).expect("Failed to map!")
-        };
+    /// creates an inaccessible anonymous mapping of the given size
+    fn reserve(addr: usize, size: usize, extra_flags: MapFlags) -> Result<usize, LoadError> {
+        let mut map_flags = MapFlags::empty();
+        map_flags.insert(MapFlags::MAP_PRIVATE);
+        map_flags.insert(MapFlags::MAP_ANONYMOUS);
+        map_flags.insert(extra_flags);
 
-        // use the load_addr to offset the virt address of each segment. If this is a 
-        // static binary, set it to 0 since the virtual addresses in the headers are absolute
-        let load_base = if mmap_addr == 0 { 
-            load_addr as usize
-        } else { 
-            0
+        unsafe {
+            mmap(addr as *mut c_void, size, ProtFlags::PROT_NONE, map_flags, -1, 0)
+                .map(|mapping| mapping as usize)
+                .map_err(|err| LoadError::Map { addr, size, err })
+        }
+    }
+
+    /// reserves the range at exactly the given address, without replacing existing mappings (MAP_FIXED_NOREPLACE)
+    fn reserve_noreplace(addr: usize, size: usize) -> Result<usize, LoadError> {
+        let mapping = match Self::reserve(addr, size, map_fixed_noreplace()) {
+            Err(LoadError::Map { err: nix::Error::Sys(Errno::EEXIST), .. }) => return Err(Self::address_in_use(addr, size)),
+            res => res?,
         };
 
-        // populate the pages of each segment and change the protection status
+        // kernels older than 4.17 treat the address as a hint only
+        if mapping != addr {
+            unsafe {
+                let _ = munmap(mapping as *mut c_void, size);
+            }
+            return Err(Self::address_in_use(addr, size));
+        }
+
+        Ok(mapping)
+    }
+
+    /// builds the error for a range that is already in use, listing the mappings that are in the way. The list
+    /// is empty if /proc/self/maps can not be read
+    fn address_in_use(addr: usize, size: usize) -> LoadError {
+        let collisions = maps::read_maps()
+            .map(|mappings| mappings.into_iter().filter(|mapping| mapping.overlaps(addr, addr + size)).collect())
+            .unwrap_or_default();
+
+        LoadError::AddressInUse { addr, size, collisions }
+    }
+
+    /// reserves the range wherever mmap() sees fit, aligned to the given alignment. To do so, a larger range
+    /// is reserved first and the excess at both ends is unmapped again
+    fn reserve_aligned(size: usize, alignment: usize) -> Result<usize, LoadError> {
+        if alignment <= ELF_MIN_ALIGNMENT {
+            return Self::reserve(0, size, MapFlags::empty());
+        }
+
+        let padded_size = size + alignment;
+        let mapping = Self::reserve(0, padded_size, MapFlags::empty())?;
+        let aligned = (mapping + alignment - 1) & !(alignment - 1);
+        let end = (aligned + size + ELF_MIN_ALIGNMENT - 1) & ELF_MIN_ALIGNMENT_MASK;
+
+        unsafe {
+            if aligned > mapping {
+                let _ = munmap(mapping as *mut c_void, aligned - mapping);
+            }
+            if mapping + padded_size > end {
+                let _ = munmap(end as *mut c_void, mapping + padded_size - end);
+            }
+        }
+
+        Ok(aligned)
+    }
+
+    /// unmaps every page of the reservation [start, start + size) that is not covered by a segment
+    fn unmap_gaps(segments: &[ElfSegment], load_base: usize, start: usize, size: usize) -> Result<(), LoadError> {
+        let end = (start + size + ELF_MIN_ALIGNMENT - 1) & ELF_MIN_ALIGNMENT_MASK;
+        let mut cursor = start;
+
         for seg in segments.iter() {
+            let seg_start = (load_base + seg.virt_addr) & ELF_MIN_ALIGNMENT_MASK;
+            let seg_end = (load_base + seg.virt_addr + seg.memsize + ELF_MIN_ALIGNMENT - 1) & ELF_MIN_ALIGNMENT_MASK;
+
+            if seg_start > cursor {
+                Self::unmap(cursor, seg_start - cursor)?;
+            }
+            cursor = cursor.max(seg_end);
+        }
 
-            // this is the same logic as in the Linux kernel for aligning addresses of
-            // program headers
-            let addr = load_base + seg.virt_addr;
-            let size = seg.filesize + (addr & (ELF_MIN_ALIGNMENT -1));
+        if end > cursor {
+            Self::unmap(cursor, end - cursor)?;
+        }
+
+        Ok(())
+    }
+
+    /// unmaps the range [addr, addr + size)
+    fn unmap(addr: usize, size: usize) -> Result<(), LoadError> {
+        unsafe {
+            munmap(addr as *mut c_void, size).map_err(|err| LoadError::Unmap { addr, size, err })
+        }
+    }
 
-            let addr = addr & ELF_MIN_ALIGNMENT_MASK;
-            let size = (size + ELF_MIN_ALIGNMENT - 1) & ELF_MIN_ALIGNMENT_MASK;
-            
-            // copy the actual number of bytes in the file. The (size) might be larger than this
-            // as the segment might contain uninitialized data
+    /// Maps a single segment the way the kernel's elf_load() does: the file backed part is mapped from the file,
+    /// the rest of the last file page is cleared and the remaining memsz is backed by fresh anonymous pages.
+    /// The protection of the segment applies to its entire memsz range
+    fn map_segment(seg: &ElfSegment, load_base: usize, prot: ProtFlags, fd: RawFd) -> Result<MappedSegment, LoadError> {
+        let addr = load_base + seg.virt_addr;
+        let file_end = addr + seg.filesize;
+        let mem_end = addr + seg.memsize;
+
+        // this is the same logic as in the Linux kernel for aligning addresses of
+        // program headers
+        let map_start = addr & ELF_MIN_ALIGNMENT_MASK;
+        let file_page_end = (file_end + ELF_MIN_ALIGNMENT - 1) & ELF_MIN_ALIGNMENT_MASK;
+        let mem_page_end = (mem_end + ELF_MIN_ALIGNMENT - 1) & ELF_MIN_ALIGNMENT_MASK;
+
+        let offset = seg.offset - (addr - map_start);
+        let mut anon_start = map_start;
+        if seg.filesize != 0 {
+            let mut file_flags = MapFlags::empty();
+            file_flags.insert(MapFlags::MAP_PRIVATE);
+            file_flags.insert(MapFlags::MAP_FIXED);
+
+            let size = file_page_end - map_start;
             unsafe {
-                libc::memcpy(addr as *mut c_void, seg.data.as_ptr() as *const c_void, seg.data.len());
+                mmap(map_start as *mut c_void, size, prot, file_flags, fd, offset as i64)
+                    .map_err(|err| LoadError::Map { addr: map_start, size, err })?;
+            }
+
+            // the last file page of the segment also contains whatever follows the segment in the file. If the
+            // segment contains uninitialized data (.bss), that part of the page has to be cleared by hand. Just like the
+            // kernel, ignore this for segments that are not writable
+            if seg.memsize > seg.filesize && prot.contains(ProtFlags::PROT_WRITE) {
+                unsafe {
+                    libc::memset(file_end as *mut c_void, 0, file_page_end - file_end);
+                }
             }
 
-            // now change the protection of the segment (with the actual size)
+            anon_start = file_page_end;
+        }
+
+        // the pages past the file backed part are fresh zero pages with the protection of the segment, just like
+        // the kernel's vm_brk_flags() mapping
+        if mem_page_end > anon_start {
+            let mut anon_flags = MapFlags::empty();
+            anon_flags.insert(MapFlags::MAP_PRIVATE);
+            anon_flags.insert(MapFlags::MAP_ANONYMOUS);
+            anon_flags.insert(MapFlags::MAP_FIXED);
+
+            let size = mem_page_end - anon_start;
             unsafe {
-                mprotect(addr as *mut c_void, size, seg.prot).expect("mprotect() failed");
+                mmap(anon_start as *mut c_void, size, prot, anon_flags, -1, 0)
+                    .map_err(|err| LoadError::Map { addr: anon_start, size, err })?;
             }
         }
 
-        ElfLoad {
-            load_addr: load_addr as usize,
+        Ok(MappedSegment {
+            start: map_start,
+            end: mem_page_end,
+            file_end: anon_start,
+            offset,
+            prot,
+        })
+    }
+
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_elf;
+    use crate::parse_elf::tests::{dyn_image, load_segment, BASE};
+
+    fn unmap_image(load: &ElfLoad) {
+        for (start, end) in load.ranges.iter() {
+            unsafe {
+                let _ = munmap(*start as *mut c_void, end - start);
+            }
         }
     }
 
+    #[test]
+    fn wrapping_pt_phdr_falls_back_to_the_file_offset() {
+        let phdr = Elf64Phdr { ptype: PT_PHDR, pflags: 4, offset: 64, vaddr: 0xffffffffffffffc0, paddr: 0, filesz: 112, memsz: 112, align: 8 };
+        let load_info = parse_elf::parse_elf_bytes(&dyn_image(&[phdr, load_segment()])).unwrap();
 
+        let load = ElfLoad::load(&load_info, &LoadConfig::default()).unwrap();
+        assert_eq!(load.phdr_addr, load.load_bias + BASE as usize + 64);
+        unmap_image(&load);
+    }
+
+    #[test]
+    fn failed_loads_release_their_reservation() {
+        // without an alignment the parser accepts any file offset, but mmap() refuses to map an unaligned one
+        let unaligned = Elf64Phdr { offset: 0x10, filesz: 0xff0, memsz: 0xff0, align: 0, ..load_segment() };
+        let broken = parse_elf::parse_elf_bytes(&dyn_image(&[unaligned])).unwrap();
+        let valid = parse_elf::parse_elf_bytes(&dyn_image(&[load_segment()])).unwrap();
+
+        // far below the area mmap() hands out addresses from, so that no other test takes it in the meantime
+        let base = 0x2000_0000_0000;
+        let config = LoadConfig { placement: Placement::Base(base), ..LoadConfig::default() };
+
+        assert!(matches!(ElfLoad::load(&broken, &config), Err(LoadError::Map { .. })));
+        let load = ElfLoad::load(&valid, &config).unwrap();
+        assert_eq!(load.load_addr, base);
+        unmap_image(&load);
+    }
 }
@@ -0,0 +1,539 @@
+use core::ffi::c_void;
+use std::arch::asm;
+use std::convert::Infallible;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+
+use crate::auxv::{self, AuxOverride, AT_RANDOM};
+use crate::brk::{self, BrkError};
+use crate::exec_reset;
+use crate::load_elf::{ElfLoad, LoadConfig, LoadError, Placement};
+use crate::parse_elf::{self, ElfError, ExecStack, LoadInfo};
+use crate::stack_setup::{self, NewStack, StackConfig, StackError, StackLayout};
+use crate::ul_exec::{Trampoline, UlExecError};
+use nix::sys::mman::munmap;
+
+
+/// Everything that can go wrong while loading a program and starting it
+#[derive(Debug)]
+pub enum LoaderError {
+    /// the program or its interpreter is not a loadable ELF file
+    Parse { file: String, err: ElfError },
+    /// the program or its interpreter could not be mapped into memory
+    Load { file: String, err: LoadError },
+    /// the program requires an executable stack, which was refused
+    ExecStack { file: String },
+    /// the initial stack could not be set up
+    Stack { file: String, err: StackError },
+    /// the program break emulation could not be set up
+    Brk(BrkError),
+    /// the SIGSYS handler that emulates brk() is part of the loader, so it can not survive a userland exec
+    UlExecWithBrk,
+    /// the process state could not be reset before the jump
+    Reset(io::Error),
+    /// the userland exec could not be prepared
+    UlExec(UlExecError),
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoaderError::Parse { file, err } => write!(f, "{}: {}", file, err),
+            LoaderError::Load { file, err } => write!(f, "{}: {}", file, err),
+            LoaderError::ExecStack { file } => write!(f, "{}: refusing to load a program that requires an executable stack", file),
+            LoaderError::Stack { file, err } => write!(f, "{}: {}", file, err),
+            LoaderError::Brk(err) => write!(f, "{}", err),
+            LoaderError::UlExecWithBrk => write!(f, "a userland exec can not be combined with the brk() emulation"),
+            LoaderError::Reset(err) => write!(f, "failed to reset the process state: {}", err),
+            LoaderError::UlExec(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {}
+
+
+/// Builds up how a program is loaded, modeled on std::process::Command:
+///
+/// ```
+/// use userspace_rust_loader::Loader;
+///
+/// let program = Loader::new("/bin/ls").args(&["-la", "/tmp"]).stack_size(1 << 20).load()?;
+/// assert!(program.rsp > program.stack().start && program.rsp < program.stack().end);
+/// # Ok::<(), userspace_rust_loader::LoaderError>(())
+/// ```
+///
+/// By default the program gets its path as argv[0], inherits the environment the calling process has when load()
+/// is called and is placed wherever mmap() sees fit
+#[derive(Debug, Clone)]
+pub struct Loader {
+    program: OsString,
+    args: Vec<OsString>,
+    /// None inherits the environment of the calling process at load time
+    env: Option<Vec<OsString>>,
+    execfn: Option<OsString>,
+    placement: Placement,
+    refuse_execstack: bool,
+    read_implies_exec: bool,
+    emulate_brk: bool,
+    ul_exec: bool,
+    reset_process_state: bool,
+    auxv_overrides: Vec<AuxOverride>,
+    random_seed: Option<[u8; 16]>,
+    stack_size: Option<usize>,
+}
+
+impl Loader {
+    /// prepares loading the program at the given path. A path of "-" reads the program from stdin
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        let program = program.as_ref().to_os_string();
+
+        Loader {
+            args: vec![program.clone()],
+            env: None,
+            program,
+            execfn: None,
+            placement: Placement::Anywhere,
+            refuse_execstack: false,
+            read_implies_exec: false,
+            emulate_brk: false,
+            ul_exec: false,
+            reset_process_state: true,
+            auxv_overrides: Vec::new(),
+            random_seed: None,
+            stack_size: None,
+        }
+    }
+
+    /// sets argv[0], which is the path of the program by default
+    pub fn arg0<S: AsRef<OsStr>>(mut self, arg0: S) -> Self {
+        self.args[0] = arg0.as_ref().to_os_string();
+        self
+    }
+
+    /// appends a single argument after argv[0] and the arguments that were already added
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// appends multiple arguments, which do not have to be UTF-8
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args.extend(args.into_iter().map(|arg| arg.as_ref().to_os_string()));
+        self
+    }
+
+    /// replaces the whole environment with the given NAME=VALUE strings, which do not have to be UTF-8
+    pub fn env<I, S>(mut self, env: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.env = Some(env.into_iter().map(|var| var.as_ref().to_os_string()).collect());
+        self
+    }
+
+    /// sets the file name AT_EXECFN points to, the path of the program by default
+    pub fn execfn<S: AsRef<OsStr>>(mut self, execfn: S) -> Self {
+        self.execfn = Some(execfn.as_ref().to_os_string());
+        self
+    }
+
+    /// decides where a position independent program is placed. The interpreter is always placed by mmap()
+    pub fn placement(mut self, placement: Placement) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    /// refuses to load programs that request an executable stack or would run with READ_IMPLIES_EXEC
+    pub fn refuse_execstack(mut self, refuse: bool) -> Self {
+        self.refuse_execstack = refuse;
+        self
+    }
+
+    /// runs programs without a PT_GNU_STACK header with READ_IMPLIES_EXEC, like kernels before 5.8 did for
+    /// x86-64 binaries. Newer kernels only do this for 32-bit binaries, so it is off by default
+    pub fn read_implies_exec(mut self, read_implies_exec: bool) -> Self {
+        self.read_implies_exec = read_implies_exec;
+        self
+    }
+
+    /// serves the brk() calls of the loaded program from a program break right past each image. The program
+    /// breaks are only reserved by LoadedProgram::run(), which fails with BrkError::TooManyImages if the
+    /// process already has 8 of them
+    pub fn emulate_brk(mut self, emulate: bool) -> Self {
+        self.emulate_brk = emulate;
+        self
+    }
+
+    /// unmaps everything of the loader right before the jump, see ul_exec::Trampoline
+    pub fn ul_exec(mut self, ul_exec: bool) -> Self {
+        self.ul_exec = ul_exec;
+        self
+    }
+
+    /// resets signal handlers, the signal mask and O_CLOEXEC descriptors like execve() before the jump. This
+    /// is the default
+    pub fn reset_process_state(mut self, reset: bool) -> Self {
+        self.reset_process_state = reset;
+        self
+    }
+
+    /// adds a change to the aux vector. The changes are applied in the order they were added
+    pub fn auxv(mut self, aux_override: AuxOverride) -> Self {
+        self.auxv_overrides.push(aux_override);
+        self
+    }
+
+    /// sets the 16 bytes AT_RANDOM points to, they are random by default
+    pub fn random_seed(mut self, seed: [u8; 16]) -> Self {
+        self.random_seed = Some(seed);
+        self
+    }
+
+    /// sets the size of the initial stack, RLIMIT_STACK by default
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// maps the program and its interpreter into memory and sets up the initial stack, without jumping to it.
+    /// Nothing but these mappings changes in the calling process, and they are unmapped again once the
+    /// LoadedProgram is dropped
+    pub fn load(self) -> Result<LoadedProgram, LoaderError> {
+        let Loader {
+            program, args, env, execfn, placement, refuse_execstack, read_implies_exec, emulate_brk, ul_exec,
+            reset_process_state, auxv_overrides, random_seed, stack_size,
+        } = self;
+
+        if ul_exec && emulate_brk {
+            return Err(LoaderError::UlExecWithBrk);
+        }
+
+        // the path is only turned into a string for messages, the file itself is opened by its raw path
+        let name = program.to_string_lossy().into_owned();
+
+        // parse the ELF file to be loaded to obtain necessary load information
+        let binary_info = parse(&program)?;
+
+        // just like the kernel, the PT_GNU_STACK header of the binary decides about the stack of the whole process.
+        // Since Linux 5.8 (see elf_read_implies_exec()), x86-64 binaries without this header get a non-executable
+        // stack and only 32-bit ones still run with READ_IMPLIES_EXEC. The old behavior is available on request
+        let read_implies_exec = read_implies_exec && binary_info.exec_stack == ExecStack::Default;
+        if refuse_execstack && (binary_info.exec_stack == ExecStack::Enabled || read_implies_exec) {
+            return Err(LoaderError::ExecStack { file: name });
+        }
+
+        // the placement only applies to the program itself, the interpreter is always placed by mmap()
+        let load_config = LoadConfig {
+            read_implies_exec,
+            placement,
+        };
+        let interp_config = LoadConfig {
+            read_implies_exec,
+            placement: Placement::Anywhere,
+        };
+
+        // load the binary into memory first, just like the kernel does. Only then the addresses of position
+        // independent binaries, static-pie ones included, are known
+        let binary_load = load(&name, &binary_info, &load_config)?;
+
+        // we will have to check if the ELF file uses an interpreter. If so, the entry point needs to be _start of that shared object file (usually ld.so)
+        let interp_load = match &binary_info.elf_interp {
+            Some(elf_interp) => match parse(elf_interp).and_then(|interp_info| load(elf_interp, &interp_info, &interp_config)) {
+                Ok(interp_load) => Some(interp_load),
+                Err(err) => {
+                    unmap_ranges(&binary_load.ranges);
+                    return Err(err);
+                },
+            },
+            None => None,
+        };
+
+        // without an interpreter, execution starts at the entry point of the binary itself and there is no ELF interpreter base (NULL)
+        let entry = interp_load.as_ref().map_or(binary_load.entry, |load| load.entry);
+        let interp_base = interp_load.as_ref().map(|load| load.load_addr);
+
+        // setup a new execution stack. The initial stack layout is the same, wether this is a static ELF_EXEC, PIE ELF_DYN or anything else for that matter
+        let stack_config = StackConfig {
+            args,
+            env: env.unwrap_or_else(inherited_env),
+            execfn: execfn.unwrap_or_else(|| program.clone()),
+            auxv_overrides,
+            random_seed,
+            executable: binary_info.exec_stack == ExecStack::Enabled || read_implies_exec,
+            size: stack_size.unwrap_or_else(StackConfig::default_size),
+        };
+        let stack = match stack_setup::setup_stack(&binary_info, &binary_load, interp_base.unwrap_or(0), &stack_config) {
+            Ok(stack) => stack,
+            Err(err) => {
+                unmap_ranges(&binary_load.ranges);
+                if let Some(interp_load) = &interp_load {
+                    unmap_ranges(&interp_load.ranges);
+                }
+                return Err(LoaderError::Stack { file: name, err });
+            },
+        };
+
+        // release the parsed file, so that neither its mapping nor its file descriptor linger in the loaded program
+        let interp = binary_info.elf_interp.clone();
+        drop(binary_info);
+
+        Ok(LoadedProgram {
+            base: binary_load.load_addr,
+            interp_base,
+            entry,
+            rsp: stack.rsp,
+            program: name,
+            binary_load,
+            interp,
+            interp_load,
+            stack,
+            read_implies_exec,
+            emulate_brk,
+            ul_exec,
+            reset_process_state,
+        })
+    }
+}
+
+/// returns the environment of the calling process as NAME=VALUE strings, just like execve() passes it on
+fn inherited_env() -> Vec<OsString> {
+    std::env::vars_os().map(|(name, value)| {
+        let mut env_var = name;
+        env_var.push("=");
+        env_var.push(value);
+        env_var
+    }).collect()
+}
+
+/// parses an ELF file, "-" reads it from stdin
+fn parse<S: AsRef<OsStr>>(file: S) -> Result<LoadInfo, LoaderError> {
+    let file = file.as_ref();
+    let load_info = if file == "-" {
+        parse_elf::parse_elf_reader(&mut io::stdin())
+    } else {
+        parse_elf::parse_elf(file)
+    };
+
+    load_info.map_err(|err| LoaderError::Parse { file: file.to_string_lossy().into_owned(), err })
+}
+
+/// maps a parsed ELF file into memory
+fn load(file: &str, load_info: &LoadInfo, config: &LoadConfig) -> Result<ElfLoad, LoaderError> {
+    ElfLoad::load(load_info, config).map_err(|err| LoaderError::Load { file: file.to_string(), err })
+}
+
+/// unmaps what was loaded for a program that will never run
+fn unmap_ranges(ranges: &[(usize, usize)]) {
+    for (start, end) in ranges {
+        unsafe {
+            let _ = munmap(*start as *mut c_void, end - start);
+        }
+    }
+}
+
+
+/// A program that is mapped into memory together with its interpreter and initial stack, ready to be started
+pub struct LoadedProgram {
+    /// the address the program was loaded at
+    pub base: usize,
+    /// the address the interpreter was loaded at, None for static programs
+    pub interp_base: Option<usize>,
+    /// where execution starts: the entry point of the interpreter, or of the program itself without one
+    pub entry: usize,
+    /// the initial stack pointer, it points to argc
+    pub rsp: usize,
+    program: String,
+    binary_load: ElfLoad,
+    interp: Option<String>,
+    interp_load: Option<ElfLoad>,
+    stack: NewStack,
+    read_implies_exec: bool,
+    emulate_brk: bool,
+    ul_exec: bool,
+    reset_process_state: bool,
+}
+
+impl LoadedProgram {
+    /// returns where the program was mapped
+    pub fn binary(&self) -> &ElfLoad {
+        &self.binary_load
+    }
+
+    /// returns where the interpreter was mapped, None for static programs
+    pub fn interp(&self) -> Option<&ElfLoad> {
+        self.interp_load.as_ref()
+    }
+
+    /// returns the initial stack and what was written onto it
+    pub fn stack(&self) -> &NewStack {
+        &self.stack
+    }
+
+    /// returns every address range the program, its interpreter and its stack occupy
+    pub fn ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges = self.binary_load.ranges.clone();
+        if let Some(interp_load) = &self.interp_load {
+            ranges.extend_from_slice(&interp_load.ranges);
+        }
+        ranges.push((self.stack.start, self.stack.end));
+        ranges
+    }
+
+    /// jumps to the entry point of the program with all registers cleared and rsp pointing to the new stack.
+    /// It only returns if the process could not be prepared for the jump
+    ///
+    /// # Safety
+    ///
+    /// The loaded program takes over the whole process: the calling thread never comes back, the memory of the
+    /// caller is shared with the program (or unmapped with ul_exec) and no other thread may be running
+    pub unsafe fn run(self) -> Result<Infallible, LoaderError> {
+        // the ranges are collected first, as nothing may allocate once brk() is trapped
+        let keep = self.ranges();
+
+        // reserve a program break past each image. The program goes first, as its break also serves all libraries
+        // that the interpreter maps. Should the jump not happen, the reservations are released again
+        let mut brk_reservations = Vec::new();
+        if self.emulate_brk {
+            brk_reservations.push(brk::reserve(&self.binary_load, brk::DEFAULT_BRK_SIZE, self.read_implies_exec).map_err(LoaderError::Brk)?);
+            if let Some(interp_load) = &self.interp_load {
+                brk_reservations.push(brk::reserve(interp_load, brk::DEFAULT_BRK_SIZE, self.read_implies_exec).map_err(LoaderError::Brk)?);
+            }
+        }
+
+        // just like execve(), do not let the signal handlers, signal mask and O_CLOEXEC descriptors of the loader and
+        // the Rust runtime leak into the loaded program
+        if self.reset_process_state {
+            exec_reset::reset().map_err(LoaderError::Reset)?;
+        }
+
+        // from here on brk() calls are trapped, so nothing may allocate anymore
+        if self.emulate_brk {
+            brk::install().map_err(LoaderError::Brk)?;
+        }
+
+        // a userland exec leaves nothing of the loader behind, only the loaded images, the new stack and the vDSO remain
+        let trampoline = if self.ul_exec {
+            Some(Trampoline::prepare(&keep, self.rsp, self.entry).map_err(LoaderError::UlExec)?)
+        } else {
+            None
+        };
+
+        // nothing can fail from here on. The personality makes the mappings the loaded program creates itself follow
+        // the same rules as the ones the loader created
+        if self.read_implies_exec {
+            let persona = libc::personality(0xffffffff);
+            libc::personality((persona | libc::READ_IMPLIES_EXEC) as libc::c_ulong);
+        }
+
+        if let Some(trampoline) = trampoline {
+            trampoline.jump();
+        }
+        jump(self.rsp, self.entry)
+    }
+}
+
+/// A program that is not run gives back everything load() mapped for it. The ranges are unmapped one by one
+/// instead of through ranges(), so that dropping does not allocate
+impl Drop for LoadedProgram {
+    fn drop(&mut self) {
+        unmap_ranges(&self.binary_load.ranges);
+        if let Some(interp_load) = &self.interp_load {
+            unmap_ranges(&interp_load.ranges);
+        }
+        unmap_ranges(&[(self.stack.start, self.stack.end)]);
+    }
+}
+
+/// Describes the whole memory layout the program starts with: where each image and segment was mapped, the stack,
+/// and the argv, envp and aux vector on it. Entries that point to the stack are followed by what they point to
+impl fmt::Display for LoadedProgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "program {}", self.program)?;
+        fmt_image(f, &self.binary_load)?;
+        if let (Some(interp), Some(interp_load)) = (&self.interp, &self.interp_load) {
+            writeln!(f, "interpreter {}", interp)?;
+            fmt_image(f, interp_load)?;
+        }
+
+        let layout = &self.stack.layout;
+        writeln!(f, "stack {:x}-{:x}, guard gap included", self.stack.start, self.stack.end)?;
+        writeln!(f, "    rsp {:#x}, {:#x} bytes used", layout.rsp, layout.size())?;
+        writeln!(f, "entry point {:#x}", self.entry)?;
+
+        writeln!(f, "argv")?;
+        for (idx, arg) in layout.argv.iter().enumerate() {
+            writeln!(f, "    [{}] {:#x}{}", idx, arg, describe_pointer(layout, *arg, false))?;
+        }
+        writeln!(f, "envp")?;
+        for (idx, env_var) in layout.envp.iter().enumerate() {
+            writeln!(f, "    [{}] {:#x}{}", idx, env_var, describe_pointer(layout, *env_var, false))?;
+        }
+        writeln!(f, "auxv")?;
+        for (key, val) in layout.auxv.iter() {
+            let name = auxv::aux_name(*key).map_or_else(|| key.to_string(), String::from);
+            writeln!(f, "    {:<20} {:#x}{}", name, val, describe_pointer(layout, *val, *key == AT_RANDOM))?;
+        }
+        Ok(())
+    }
+}
+
+/// writes the addresses and segments of an image
+fn fmt_image(f: &mut fmt::Formatter, load: &ElfLoad) -> fmt::Result {
+    writeln!(f, "    base {:#x}, load bias {:#x}, entry {:#x}, program headers at {:#x}", load.load_addr, load.load_bias, load.entry, load.phdr_addr)?;
+    for segment in load.segments.iter() {
+        writeln!(f, "    {}", segment)?;
+    }
+    Ok(())
+}
+
+/// returns what a pointer into the data area of the stack points to, preceded by a space: a quoted string, or the
+/// bytes in hex. Other values yield an empty string
+fn describe_pointer(layout: &StackLayout, addr: usize, raw: bool) -> String {
+    let data = match layout.data.iter().find(|(data_addr, _)| *data_addr == addr) {
+        Some((_, data)) => data,
+        None => return String::new(),
+    };
+
+    if raw {
+        data.iter().fold(String::from(" "), |hex, byte| hex + &format!("{:02x}", byte))
+    } else {
+        let string = data.split(|byte| *byte == 0).next().unwrap_or_default();
+        format!(" {:?}", String::from_utf8_lossy(string))
+    }
+}
+
+/// kicks off execution by clearing all registers, switching to the new stack and jumping to the entry point
+unsafe fn jump(rsp: usize, entry: usize) -> ! {
+    asm!("
+        mov rsp, rdi
+        push rsi
+
+        xor rax, rax
+        xor rbx, rbx
+        xor rcx, rcx
+        xor rdx, rdx
+        xor rdi, rdi
+        xor rsi, rsi
+
+        xor r9, r9
+        xor r10, r10
+        xor r11, r11
+        xor r12, r12
+        xor r13, r13
+        xor r14, r14
+        xor r15, r15
+
+        ret
+        ",
+        in("rdi") rsp,
+        in("rsi") entry,
+        options(noreturn)
+    );
+}
@@ -1,70 +1,79 @@
-// enable asm to jump to the entry point of the program
-#![feature(asm)]
+mod options;
 
-mod load_elf;
-mod parse_elf;
-mod stack_setup;
+use std::ffi::OsString;
+use std::io::Write;
+use std::process::exit;
 
-fn main() {
-
-    // ensure that there is at least one argument to this program, it is the program that should be loaded
-    let args: Vec<String> = std::env::args().collect();
-    println!("{:?}", args);
-    if args.len() == 1 {
-        panic!("Usage: {} /PATH/TO/PROGRAM/TO/LOAD", args[0]);
-    }
+use options::Options;
+use userspace_rust_loader::parse_elf::ElfError;
+use userspace_rust_loader::{Loader, LoaderError};
 
-    // parse the ELF file to be loaded to obtain necessary load information
-    let binary_info = parse_elf::parse_elf(&args[1]);
+/// exit code used by shells when a program could not be found
+const EXIT_NOT_FOUND: i32 = 127;
 
-    // we will have to check if the ELF file uses an interpreter. If so, the entry point needs to be _start of that shared object file (usually ld.so)
-    let (entry_point, interp_base) = if let Some(elf_interp) = &binary_info.elf_interp {
-                        let loader_info = parse_elf::parse_elf(elf_interp);
-                        let loader_load = load_elf::ElfLoad::load(&loader_info);
-                        
-                        // the loader is PIE so offsts such as the entry point are relative to its load address. Figure out where the loader will load it 
-                        // to ensure we have the correct entry point and base address for the stack
-                        (loader_info.entry_point + loader_load.load_addr, loader_load.load_addr)
-                    } else {
-                        // otherwise the entry point is absolute and there is no ELF interpreter base (NULL)
-                        (binary_info.entry_point, 0)
-                    };
+/// exit code used by shells when a program was found but could not be executed
+const EXIT_CANNOT_EXECUTE: i32 = 126;
 
-    // load the binary into memory
-    let binary_load = load_elf::ElfLoad::load(&binary_info);
-
-    // setup a new execution stack. The initial stack layout is the same, wether this is a static ELF_EXEC, PIE ELF_DYN or anything else for that matter
-    // save the RSP so that we can jump to it later
-    let rsp = stack_setup::setup_stack(&binary_info, binary_load.load_addr, interp_base);
+/// prints a diagnostic and exits with the same exit codes a shell would use
+fn fail(loader: &str, err: LoaderError) -> ! {
+    eprintln!("{}: {}", loader, err);
+    match err {
+        LoaderError::Parse { err: ElfError::Io(ref io_err), .. } if io_err.kind() == std::io::ErrorKind::NotFound => exit(EXIT_NOT_FOUND),
+        _ => exit(EXIT_CANNOT_EXECUTE),
+    }
+}
 
+fn main() {
 
-    // kick off execution by clearing all registers, switching to the new stack and jumping to the entry point
-    unsafe {
-        asm!("
-            mov rsp, rax
-            push rbx
+    // ensure that there is at least one argument to this program, it is the program that should be loaded. Neither
+    // the arguments nor the environment have to be UTF-8, so they are passed on as they are
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let loader_name = args.first().map_or_else(|| String::from("loader"), |arg0| arg0.to_string_lossy().into_owned());
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}: {}\n{}", loader_name, err, Options::usage(&loader_name));
+            exit(1);
+        }
+    };
 
-            xor rax, rax
-            xor rbx, rbx
-            xor rcx, rcx
-            xor rdx, rdx
-            xor rdi, rdi
-            xor rsi, rsi
+    // the options already contain the complete argv and environment of the program
+    let mut program_args = options.args.into_iter();
+    let mut loader = Loader::new(options.program)
+        .arg0(program_args.next().unwrap_or_default())
+        .args(program_args)
+        .env(options.env)
+        .placement(options.placement)
+        .refuse_execstack(options.refuse_execstack)
+        .read_implies_exec(options.read_implies_exec)
+        .emulate_brk(options.emulate_brk)
+        .ul_exec(options.ul_exec)
+        .reset_process_state(!options.keep_process_state);
+    for aux_override in options.auxv_overrides {
+        loader = loader.auxv(aux_override);
+    }
+    if let Some(seed) = options.random_seed {
+        loader = loader.random_seed(seed);
+    }
+    if let Some(size) = options.stack_size {
+        loader = loader.stack_size(size);
+    }
 
-            xor r9, r9
-            xor r10, r10
-            xor r11, r11
-            xor r12, r12
-            xor r13, r13
-            xor r14, r14
-            xor r15, r15
+    let program = match loader.load() {
+        Ok(program) => program,
+        Err(err) => fail(&loader_name, err),
+    };
 
-            ret
-            ",
-            in("rax") rsp,
-            in("rbx") entry_point
-        );
+    // everything is mapped just like for a real run, so these are the addresses the program would start with.
+    // A closed stdout, e.g. when piped into head, is not an error
+    if options.dry_run {
+        let _ = write!(std::io::stdout(), "{}", program);
+        exit(0);
     }
 
-
-}
\ No newline at end of file
+    let err = match unsafe { program.run() } {
+        Ok(never) => match never {},
+        Err(err) => err,
+    };
+    fail(&loader_name, err);
+}
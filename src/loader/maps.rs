@@ -0,0 +1,81 @@
+use std::fmt;
+use std::io;
+
+/// the special mappings the kernel sets up for every process. They are not part of the loader and can not be
+/// recreated once they are gone
+const KERNEL_MAPPINGS: [&str; 4] = ["[vdso]", "[vvar]", "[vvar_vclock]", "[vsyscall]"];
+
+/// Represents a single line of /proc/self/maps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: usize,
+    pub end: usize,
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+    pub shared: bool,
+    pub offset: usize,
+    pub inode: u64,
+    /// the file backing the mapping or a pseudo name such as [heap], empty for anonymous mappings
+    pub path: String,
+}
+
+impl Mapping {
+    /// returns true for the special mappings the kernel creates, such as the vDSO
+    pub fn is_kernel_mapping(&self) -> bool {
+        KERNEL_MAPPINGS.contains(&self.path.as_str())
+    }
+
+    /// returns true if the mapping overlaps the range [start, end)
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// parses one line of /proc/self/maps
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(6, ' ');
+        let (start, end) = fields.next()?.split_once('-')?;
+        let perms = fields.next()?.as_bytes();
+        let offset = fields.next()?;
+        let _dev = fields.next()?;
+        let inode = fields.next()?;
+        let path = fields.next().unwrap_or("").trim_start();
+
+        if perms.len() != 4 {
+            return None;
+        }
+
+        Some(Mapping {
+            start: usize::from_str_radix(start, 16).ok()?,
+            end: usize::from_str_radix(end, 16).ok()?,
+            readable: perms[0] == b'r',
+            writable: perms[1] == b'w',
+            executable: perms[2] == b'x',
+            shared: perms[3] == b's',
+            offset: usize::from_str_radix(offset, 16).ok()?,
+            inode: inode.parse().ok()?,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:x}-{:x} {}{}{}{} {:08x} {}",
+            self.start, self.end,
+            if self.readable { 'r' } else { '-' },
+            if self.writable { 'w' } else { '-' },
+            if self.executable { 'x' } else { '-' },
+            if self.shared { 's' } else { 'p' },
+            self.offset, self.path)
+    }
+}
+
+/// reads and parses /proc/self/maps
+pub fn read_maps() -> Result<Vec<Mapping>, io::Error> {
+    let maps = std::fs::read_to_string("/proc/self/maps")?;
+
+    maps.lines()
+        .map(|line| Mapping::parse(line).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed line in /proc/self/maps: {}", line))))
+        .collect()
+}
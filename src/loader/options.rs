@@ -0,0 +1,360 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+use userspace_rust_loader::auxv::{self, AuxOverride, AT_RANDOM};
+use userspace_rust_loader::load_elf::Placement;
+
+/// Command line options of the loader. Options of the loader itself come first and end at the
+/// first argument that is not an option (or at "--"). That argument is the program to load and
+/// everything after it is passed on to the loaded program.
+pub struct Options {
+    /// refuse to load programs that request an executable stack through PT_GNU_STACK
+    pub refuse_execstack: bool,
+    /// run programs without a PT_GNU_STACK header with READ_IMPLIES_EXEC, like kernels before 5.8
+    pub read_implies_exec: bool,
+    /// where a position independent program is placed
+    pub placement: Placement,
+    /// serve brk() calls of the loaded program from a program break of its own
+    pub emulate_brk: bool,
+    /// unmap the loader itself before jumping to the program
+    pub ul_exec: bool,
+    /// leave signal handlers, the signal mask and O_CLOEXEC descriptors of the loader as they are
+    pub keep_process_state: bool,
+    /// load the program and print the resulting memory layout instead of running it
+    pub dry_run: bool,
+    /// changes to the aux vector of the loaded program, in the order they were given
+    pub auxv_overrides: Vec<AuxOverride>,
+    /// the bytes AT_RANDOM points to
+    pub random_seed: Option<[u8; 16]>,
+    /// the size of the initial stack, RLIMIT_STACK is used if None
+    pub stack_size: Option<usize>,
+    /// the path of the program to load, "-" to read the program from stdin
+    pub program: OsString,
+    /// the arguments passed to the loaded program, argv[0] included
+    pub args: Vec<OsString>,
+    /// the environment of the loaded program as NAME=VALUE strings. Just like arguments, they do not have to be UTF-8
+    pub env: Vec<OsString>,
+}
+
+/// a change to the environment of the loaded program, applied in the order given on the command line
+enum EnvChange {
+    Set(OsString, OsString),
+    Unset(OsString),
+}
+
+impl Options {
+
+    /// returns the usage message of the loader
+    pub fn usage(loader: &str) -> String {
+        format!("Usage: {} [OPTIONS] /PATH/TO/PROGRAM/TO/LOAD [ARGS...]
+       {} [OPTIONS] - [ARGS...]  (read the program from stdin)
+
+Options:
+    --refuse-execstack  refuse to load programs that request an executable stack
+    --read-implies-exec run programs without PT_GNU_STACK with READ_IMPLIES_EXEC, like Linux before 5.8
+    --kernel-placement  place PIE programs at ELF_ET_DYN_BASE plus ASLR entropy, like the kernel does. If the
+                        loader itself is in the way, e.g. without ASLR, mmap() chooses the location instead
+    --base ADDR         load a PIE program at the given page aligned address
+    --emulate-brk       give each loaded image its own program break by trapping brk() with seccomp
+    --auxv NAME=VALUE   set an aux vector entry, e.g. AT_SECURE=1. AT_RANDOM takes the 16 bytes as 32 hex digits
+    --auxv-drop NAME    remove an aux vector entry, e.g. AT_SYSINFO_EHDR
+    --ul-exec           unmap the loader, its heap and its stack before jumping to the program
+    --stack-size SIZE   the size of the initial stack in bytes, defaults to RLIMIT_STACK
+    --keep-process-state
+                        do not reset signal handlers, the signal mask and O_CLOEXEC descriptors like execve()
+    --dry-run           print the mappings, entry point, argv, envp and auxv of the program instead of running it
+
+Environment and arguments, like env(1) and exec -a:
+    -i, --ignore-environment  start with an empty environment
+    -u, --unset NAME          remove NAME from the environment
+    NAME=VALUE                set NAME to VALUE in the environment
+    --env-file PATH           set the NAME=VALUE lines of PATH, blank lines and # comments are skipped
+    -a NAME                   pass NAME as argv[0] instead of the path of the program", loader, loader)
+    }
+
+    /// parses the command line of the loader, args[0] being the path of the loader itself. Only the values of
+    /// options have to be UTF-8, the program, its arguments and the environment are taken as they are
+    pub fn parse(args: &[OsString]) -> Result<Self, String> {
+        let mut refuse_execstack = false;
+        let mut read_implies_exec = false;
+        let mut placement = Placement::Anywhere;
+        let mut emulate_brk = false;
+        let mut keep_process_state = false;
+        let mut dry_run = false;
+        let mut ul_exec = false;
+        let mut auxv_overrides = Vec::new();
+        let mut random_seed = None;
+        let mut stack_size = None;
+        let mut ignore_environment = false;
+        let mut env_changes = Vec::new();
+        let mut argv0 = None;
+
+        let mut idx = 1;
+        while idx < args.len() {
+            match args[idx].as_bytes() {
+                b"--refuse-execstack" => refuse_execstack = true,
+                b"--read-implies-exec" => read_implies_exec = true,
+                b"--kernel-placement" => placement = Placement::Kernel,
+                b"--emulate-brk" => emulate_brk = true,
+                b"--keep-process-state" => keep_process_state = true,
+                b"--dry-run" => dry_run = true,
+                b"--ul-exec" => ul_exec = true,
+                b"--base" => {
+                    idx += 1;
+                    let value = utf8(args.get(idx).ok_or("--base requires an address")?)?;
+                    placement = Placement::Base(parse_number(value)?);
+                },
+                b"--auxv" => {
+                    idx += 1;
+                    let value = utf8(args.get(idx).ok_or("--auxv requires NAME=VALUE")?)?;
+                    let (name, value) = value.split_once('=').ok_or_else(|| format!("invalid aux vector entry {}, expected NAME=VALUE", value))?;
+                    match parse_aux_type(name)? {
+                        AT_RANDOM => random_seed = Some(parse_seed(value)?),
+                        aux_type => auxv_overrides.push(AuxOverride::Set(aux_type, parse_number(value)? as u64)),
+                    }
+                },
+                b"--auxv-drop" => {
+                    idx += 1;
+                    let name = utf8(args.get(idx).ok_or("--auxv-drop requires the name of an entry")?)?;
+                    auxv_overrides.push(AuxOverride::Drop(parse_aux_type(name)?));
+                },
+                b"--stack-size" => {
+                    idx += 1;
+                    let value = utf8(args.get(idx).ok_or("--stack-size requires a size")?)?;
+                    stack_size = Some(parse_number(value)?);
+                },
+                b"-i" | b"--ignore-environment" => ignore_environment = true,
+                b"-u" | b"--unset" => {
+                    idx += 1;
+                    let name = args.get(idx).ok_or("--unset requires the name of a variable")?;
+                    env_changes.push(EnvChange::Unset(name.clone()));
+                },
+                b"--env-file" => {
+                    idx += 1;
+                    let path = args.get(idx).ok_or("--env-file requires a path")?;
+                    env_changes.extend(parse_env_file(path)?);
+                },
+                b"-a" => {
+                    idx += 1;
+                    argv0 = Some(args.get(idx).ok_or("-a requires the argv[0] to pass")?.clone());
+                },
+                b"--" => {
+                    idx += 1;
+                    break;
+                },
+                // a lone "-" is the program read from stdin
+                option if option.starts_with(b"-") && option != b"-" => {
+                    return Err(format!("unknown option {}", String::from_utf8_lossy(option)));
+                },
+                assignment => match split_assignment(OsStr::from_bytes(assignment)) {
+                    Some((name, value)) => env_changes.push(EnvChange::Set(name, value)),
+                    None => break,
+                },
+            }
+            idx += 1;
+        }
+
+        // the SIGSYS handler that emulates brk() is part of the loader, so it can not survive the loader
+        if ul_exec && emulate_brk {
+            return Err(String::from("--ul-exec and --emulate-brk can not be combined"));
+        }
+
+        if idx >= args.len() {
+            return Err(String::from("no program to load was given"));
+        }
+
+        // just like execve() from a shell, argv[0] is the path of the program unless it was explicitly chosen
+        let mut program_args = vec![argv0.unwrap_or_else(|| args[idx].clone())];
+        program_args.extend_from_slice(&args[idx + 1..]);
+
+        // just like env(1), the loaded program starts out with the environment of the loader
+        let mut env: Vec<(OsString, OsString)> = if ignore_environment {
+            Vec::new()
+        } else {
+            std::env::vars_os().collect()
+        };
+        for change in env_changes {
+            match change {
+                EnvChange::Set(name, value) => match env.iter_mut().find(|(var, _)| *var == name) {
+                    Some(var) => var.1 = value,
+                    None => env.push((name, value)),
+                },
+                EnvChange::Unset(name) => env.retain(|(var, _)| *var != name),
+            }
+        }
+
+        Ok(Options {
+            refuse_execstack,
+            read_implies_exec,
+            placement,
+            emulate_brk,
+            keep_process_state,
+            dry_run,
+            ul_exec,
+            auxv_overrides,
+            random_seed,
+            stack_size,
+            program: args[idx].clone(),
+            args: program_args,
+            env: env.into_iter().map(|(mut name, value)| {
+                name.push("=");
+                name.push(value);
+                name
+            }).collect(),
+        })
+    }
+}
+
+/// returns the value of an option, which has to be UTF-8
+fn utf8(value: &OsStr) -> Result<&str, String> {
+    value.to_str().ok_or_else(|| format!("invalid value {}, expected UTF-8", value.to_string_lossy()))
+}
+
+/// splits a NAME=VALUE assignment at the first '=', None if there is none
+fn split_assignment(assignment: &OsStr) -> Option<(OsString, OsString)> {
+    let bytes = assignment.as_bytes();
+    let pos = bytes.iter().position(|byte| *byte == b'=')?;
+    Some((OsStr::from_bytes(&bytes[..pos]).to_os_string(), OsStr::from_bytes(&bytes[pos + 1..]).to_os_string()))
+}
+
+/// parses a decimal or 0x prefixed hexadecimal number
+fn parse_number(value: &str) -> Result<usize, String> {
+    let res = if value.starts_with("0x") || value.starts_with("0X") {
+        usize::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<usize>()
+    };
+
+    res.map_err(|_| format!("invalid number {}", value))
+}
+
+/// reads the NAME=VALUE lines of an environment file. Blank lines and lines starting with # are skipped
+fn parse_env_file(path: &OsStr) -> Result<Vec<EnvChange>, String> {
+    let contents = std::fs::read(path).map_err(|err| format!("{}: {}", path.to_string_lossy(), err))?;
+    let is_blank = |line: &[u8]| line.iter().all(u8::is_ascii_whitespace);
+    let is_comment = |line: &[u8]| line.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'#');
+
+    contents.split(|byte| *byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .enumerate()
+        .filter(|(_, line)| !is_blank(line) && !is_comment(line))
+        .map(|(num, line)| match split_assignment(OsStr::from_bytes(line)) {
+            Some((name, value)) if !name.is_empty() => Ok(EnvChange::Set(name, value)),
+            _ => Err(format!("{}:{}: expected NAME=VALUE", path.to_string_lossy(), num + 1)),
+        })
+        .collect()
+}
+
+/// parses the name or number of an aux vector entry
+fn parse_aux_type(name: &str) -> Result<u64, String> {
+    auxv::aux_type(name).ok_or_else(|| format!("unknown aux vector entry {}", name))
+}
+
+/// parses the 16 bytes of an AT_RANDOM seed given as 32 hexadecimal digits
+fn parse_seed(value: &str) -> Result<[u8; 16], String> {
+    let invalid = || format!("invalid AT_RANDOM seed {}, expected 32 hex digits", value);
+    if value.len() != 32 || !value.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut seed = [0u8; 16];
+    for (idx, byte) in seed.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[idx * 2..idx * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok(seed)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use userspace_rust_loader::auxv::{AT_HWCAP2, AT_SECURE, AT_SYSINFO_EHDR};
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let args: Vec<OsString> = std::iter::once("loader").chain(args.iter().copied()).map(OsString::from).collect();
+        Options::parse(&args)
+    }
+
+    #[test]
+    fn splits_loader_options_from_the_program() {
+        let options = parse(&["--refuse-execstack", "--base", "0x10000", "/bin/ls", "--base", "-la"]).unwrap();
+        assert!(options.refuse_execstack);
+        assert!(!options.read_implies_exec);
+        assert_eq!(options.placement, Placement::Base(0x10000));
+        assert_eq!(options.program, "/bin/ls");
+        assert_eq!(options.args, ["/bin/ls", "--base", "-la"]);
+
+        let options = parse(&["--", "--not-an-option", "arg"]).unwrap();
+        assert_eq!(options.program, "--not-an-option");
+
+        let options = parse(&["-", "arg"]).unwrap();
+        assert_eq!(options.program, "-");
+    }
+
+    #[test]
+    fn read_implies_exec_is_opt_in() {
+        let options = parse(&["--read-implies-exec", "/bin/true"]).unwrap();
+        assert!(options.read_implies_exec);
+        assert!(!options.refuse_execstack);
+    }
+
+    #[test]
+    fn controls_argv0_and_environment() {
+        let options = parse(&["-i", "-a", "sh", "A=1", "B=2", "-u", "A", "B=3", "/bin/busybox", "C=4"]).unwrap();
+        assert_eq!(options.args, ["sh", "C=4"]);
+        assert_eq!(options.env, ["B=3"]);
+    }
+
+    #[test]
+    fn passes_on_arguments_and_environment_that_are_not_utf8() {
+        let args: Vec<OsString> = [&b"loader"[..], b"-i", b"A=\xff", b"/bin/\xfe", b"\xfd"].iter()
+            .map(|arg| OsStr::from_bytes(arg).to_os_string())
+            .collect();
+        let options = Options::parse(&args).unwrap();
+        assert_eq!(options.program.as_bytes(), b"/bin/\xfe");
+        assert_eq!(options.args[1].as_bytes(), b"\xfd");
+        assert_eq!(options.env[0].as_bytes(), b"A=\xff");
+
+        let args: Vec<OsString> = [&b"loader"[..], b"--base", b"\xff", b"/bin/ls"].iter()
+            .map(|arg| OsStr::from_bytes(arg).to_os_string())
+            .collect();
+        assert!(Options::parse(&args).is_err());
+    }
+
+    #[test]
+    fn parses_aux_vector_overrides() {
+        let options = parse(&["--auxv", "AT_SECURE=1", "--auxv", "hwcap2=0x2", "--auxv", "99=5", "--auxv-drop", "sysinfo_ehdr", "/bin/ls"]).unwrap();
+        assert_eq!(options.auxv_overrides, [
+            AuxOverride::Set(AT_SECURE, 1),
+            AuxOverride::Set(AT_HWCAP2, 2),
+            AuxOverride::Set(99, 5),
+            AuxOverride::Drop(AT_SYSINFO_EHDR),
+        ]);
+    }
+
+    #[test]
+    fn parses_random_seed() {
+        let options = parse(&["--auxv", "AT_RANDOM=00112233445566778899aabbccddeeFF", "/bin/ls"]).unwrap();
+        assert_eq!(options.random_seed, Some([0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]));
+        assert!(options.auxv_overrides.is_empty());
+
+        assert!(parse_seed("0011223344556677").is_err());
+        assert!(parse_seed("00112233445566778899aabbccddeegg").is_err());
+        assert!(parse_seed("0011223344556677889\u{e4}aabbccddeef").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_command_lines() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--emulate-brk"]).is_err());
+        assert!(parse(&["--frobnicate", "/bin/ls"]).is_err());
+        assert!(parse(&["--base"]).is_err());
+        assert!(parse(&["--base", "0xzz", "/bin/ls"]).is_err());
+        assert!(parse(&["--ul-exec", "--emulate-brk", "/bin/ls"]).is_err());
+        assert!(parse(&["--auxv", "AT_SECURE", "/bin/ls"]).is_err());
+        assert!(parse(&["--auxv", "AT_NOPE=1", "/bin/ls"]).is_err());
+        assert!(parse(&["--auxv-drop", "AT_NULL", "/bin/ls"]).is_err());
+        assert!(parse(&["--auxv", "AT_RANDOM=42", "/bin/ls"]).is_err());
+    }
+}
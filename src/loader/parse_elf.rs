@@ -1,13 +1,45 @@
-use std::io::prelude::*;
 use std::fs::File;
+use std::fmt;
+use std::io::prelude::*;
+use std::ops::Deref;
+use std::path::Path;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
-use crate::load_elf::ElfSegment;
+use core::ffi::c_void;
+use nix::sys::mman::{
+    mmap,
+    munmap,
+    ProtFlags,
+    MapFlags
+};
+
+use crate::load_elf::{ElfSegment, TASK_SIZE};
 
 /// value for a PT_LOAD program header type
-const PT_LOAD: u32 = 0x01;
+pub const PT_LOAD: u32 = 0x01;
+
+/// value for a PT_DYNAMIC (dynamic linking information) program header type
+pub const PT_DYNAMIC: u32 = 0x02;
 
 /// value for a PT_INTERP (ELF Interpreter) program header type
-const PT_INTERP: u32 = 0x03;
+pub const PT_INTERP: u32 = 0x03;
+
+/// value for a PT_NOTE program header type
+pub const PT_NOTE: u32 = 0x04;
+
+/// value for a PT_PHDR (location of the program header table itself) program header type
+pub const PT_PHDR: u32 = 0x06;
+
+/// value for a PT_TLS (thread local storage template) program header type
+pub const PT_TLS: u32 = 0x07;
+
+/// value for a PT_GNU_STACK (stack permissions) program header type
+pub const PT_GNU_STACK: u32 = 0x6474e551;
+
+/// flags of the p_flags field of a program header
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
 
 /// standard size of a 64bit ELF header
 const SIZE_OF_ELF_HDR: usize = 64;
@@ -15,6 +47,15 @@ const SIZE_OF_ELF_HDR: usize = 64;
 /// standard size of a program header entry
 const SIZE_OF_PROGRAM_HDR: u16 = 56;
 
+/// standard size of a section header entry
+const SIZE_OF_SECTION_HDR: u16 = 64;
+
+/// e_shstrndx value of files without a section header string table
+const SHN_UNDEF: usize = 0;
+
+/// special e_shstrndx value signaling that the real index is stored in sh_link of section 0
+const SHN_XINDEX: u16 = 0xffff;
+
 /// the value of the e_ident[EI_CLASS] field for a 64bit ELF
 const CLASS_64_BIT: u8 = 0x2;
 
@@ -37,6 +78,141 @@ const X86_MACHINE: u16 = 0x3;
 const AMD64_MACHINE: u16 = 0x3e;
 
 
+/// section types (sh_type) found in ordinary x86-64 ELF files
+pub const SHT_NULL: u32 = 0;
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_HASH: u32 = 5;
+pub const SHT_DYNAMIC: u32 = 6;
+pub const SHT_NOTE: u32 = 7;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_REL: u32 = 9;
+pub const SHT_DYNSYM: u32 = 11;
+pub const SHT_INIT_ARRAY: u32 = 14;
+pub const SHT_FINI_ARRAY: u32 = 15;
+pub const SHT_GNU_HASH: u32 = 0x6ffffff6;
+
+/// section flags (sh_flags)
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+pub const SHF_MERGE: u64 = 0x10;
+pub const SHF_STRINGS: u64 = 0x20;
+pub const SHF_INFO_LINK: u64 = 0x40;
+pub const SHF_TLS: u64 = 0x400;
+
+
+
+/// Everything that can go wrong while reading and parsing an ELF file
+#[derive(Debug)]
+pub enum ElfError {
+    /// the file could not be opened or read
+    Io(std::io::Error),
+    /// the file is too small to contain an ELF header
+    TruncatedHeader(usize),
+    /// the file does not start with the ELF magic bytes
+    BadMagic(u32),
+    /// e_ident[EI_CLASS] is not ELFCLASS64
+    WrongClass(u8),
+    /// the ELF is neither ET_EXEC nor ET_DYN
+    UnsupportedType(u16),
+    /// the OS ABI is neither Linux nor System-V
+    UnsupportedAbi(u8),
+    /// the ELF is not built for x86-64
+    UnsupportedMachine(u16),
+    /// e_phentsize differs from sizeof(Elf64_Phdr)
+    BadPhentSize(u16),
+    /// the program header table lies (partially) outside of the file
+    PhdrOutOfBounds { offset: u64, num: u16 },
+    /// the file contents of a PT_LOAD segment lie outside of the file
+    SegmentOutOfBounds { offset: u64, filesz: u64 },
+    /// the PT_INTERP segment is out of bounds, not NULL terminated or not valid UTF-8
+    BadInterp,
+    /// e_shentsize differs from sizeof(Elf64_Shdr)
+    BadShentSize(u16),
+    /// the section header table lies (partially) outside of the file
+    ShdrOutOfBounds { offset: u64, num: usize },
+    /// e_shstrndx does not refer to a string table inside of the file
+    BadShstrndx(usize),
+    /// the name of a section does not lie within the section header string table
+    BadSectionName(u32),
+    /// a symbol table section is out of bounds or its entry size is not sizeof(Elf64_Sym)
+    BadSymbolTable(String),
+    /// the string table linked to a symbol table is out of bounds or does not contain a symbol name
+    BadStringTable(String),
+    /// the PT_DYNAMIC segment lies outside of the file or is not terminated by DT_NULL
+    BadDynamic,
+    /// a dynamic entry refers to a string that is not within DT_STRTAB
+    BadDynamicString(u64),
+    /// the ELF does not contain a single PT_LOAD segment
+    NoLoadSegments,
+    /// PT_LOAD segments must be sorted by their virtual address
+    UnsortedSegments { vaddr: u64, prev_vaddr: u64 },
+    /// a PT_LOAD segment overlaps with the previous one
+    OverlappingSegments { vaddr: u64, prev_end: u64 },
+    /// p_align of a PT_LOAD segment is not a power of two
+    BadSegmentAlignment { vaddr: u64, align: u64 },
+    /// p_offset and p_vaddr of a PT_LOAD segment are not congruent modulo p_align
+    MisalignedSegment { vaddr: u64, offset: u64, align: u64 },
+    /// a PT_LOAD segment has more bytes in the file than in memory
+    FileszExceedsMemsz { vaddr: u64, filesz: u64, memsz: u64 },
+    /// a PT_LOAD segment wraps around the end of the address space or ends beyond the user address space
+    SegmentWrapsAround { vaddr: u64, memsz: u64 },
+    /// the entry point does not lie within an executable PT_LOAD segment
+    EntryNotExecutable(u64),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Io(err) => write!(f, "{}", err),
+            ElfError::TruncatedHeader(size) => write!(f, "the file is too small ({} bytes) to contain an ELF header", size),
+            ElfError::BadMagic(magic) => write!(f, "no ELF magic header was found (got {:#010x})", magic),
+            ElfError::WrongClass(class) => write!(f, "only 64-bit ELFs are supported (EI_CLASS is {})", class),
+            ElfError::UnsupportedType(etype) => write!(f, "only ET_EXEC and ET_DYN ELFs can be loaded (e_type is {})", etype),
+            ElfError::UnsupportedAbi(abi) => write!(f, "only the Linux and System-V ABIs are supported (EI_OSABI is {})", abi),
+            ElfError::UnsupportedMachine(machine) => write!(f, "only x86-64 ELFs are supported (e_machine is {:#x})", machine),
+            ElfError::BadPhentSize(size) => write!(f, "program header entry size {} differs from the standard Elf64_Phdr size {}", size, SIZE_OF_PROGRAM_HDR),
+            ElfError::PhdrOutOfBounds { offset, num } => write!(f, "{} program headers at offset {:#x} do not fit into the file", num, offset),
+            ElfError::SegmentOutOfBounds { offset, filesz } => write!(f, "PT_LOAD segment at offset {:#x} with size {:#x} does not fit into the file", offset, filesz),
+            ElfError::BadInterp => write!(f, "the PT_INTERP segment does not contain a valid path"),
+            ElfError::BadShentSize(size) => write!(f, "section header entry size {} differs from the standard Elf64_Shdr size {}", size, SIZE_OF_SECTION_HDR),
+            ElfError::ShdrOutOfBounds { offset, num } => write!(f, "{} section headers at offset {:#x} do not fit into the file", num, offset),
+            ElfError::BadShstrndx(idx) => write!(f, "section {} is not a valid section header string table", idx),
+            ElfError::BadSectionName(name) => write!(f, "section name offset {:#x} lies outside of the section header string table", name),
+            ElfError::BadSymbolTable(section) => write!(f, "the symbol table {} is malformed", section),
+            ElfError::BadStringTable(section) => write!(f, "the string table linked to {} is malformed", section),
+            ElfError::BadDynamic => write!(f, "the PT_DYNAMIC segment is malformed"),
+            ElfError::BadDynamicString(offset) => write!(f, "dynamic string at offset {:#x} lies outside of DT_STRTAB", offset),
+            ElfError::NoLoadSegments => write!(f, "the ELF does not contain any PT_LOAD segments"),
+            ElfError::UnsortedSegments { vaddr, prev_vaddr } => write!(f, "PT_LOAD segment at {:#x} follows the segment at {:#x}, segments must be sorted by address", vaddr, prev_vaddr),
+            ElfError::OverlappingSegments { vaddr, prev_end } => write!(f, "PT_LOAD segment at {:#x} overlaps with the previous segment ending at {:#x}", vaddr, prev_end),
+            ElfError::BadSegmentAlignment { vaddr, align } => write!(f, "PT_LOAD segment at {:#x} has an alignment of {:#x}, which is not a power of two", vaddr, align),
+            ElfError::MisalignedSegment { vaddr, offset, align } => write!(f, "PT_LOAD segment at {:#x} has file offset {:#x}, which is not congruent to its address modulo {:#x}", vaddr, offset, align),
+            ElfError::FileszExceedsMemsz { vaddr, filesz, memsz } => write!(f, "PT_LOAD segment at {:#x} has a file size of {:#x}, which exceeds its memory size of {:#x}", vaddr, filesz, memsz),
+            ElfError::SegmentWrapsAround { vaddr, memsz } => write!(f, "PT_LOAD segment at {:#x} with size {:#x} does not fit into the user address space", vaddr, memsz),
+            ElfError::EntryNotExecutable(entry) => write!(f, "the entry point {:#x} does not lie within an executable PT_LOAD segment", entry),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ElfError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ElfError {
+    fn from(err: std::io::Error) -> Self {
+        ElfError::Io(err)
+    }
+}
+
 
 /// Represents an Elf64_Phdr as found in an actual ELF file
 #[repr(C, packed)]
@@ -52,6 +228,55 @@ pub struct Elf64Phdr {
     pub align:  u64
 }
 
+/// Represents an Elf64_Shdr as found in an actual ELF file
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct Elf64Shdr {
+    name:       u32,
+    stype:      u32,
+    flags:      u64,
+    addr:       u64,
+    offset:     u64,
+    size:       u64,
+    link:       u32,
+    info:       u32,
+    addralign:  u64,
+    entsize:    u64
+}
+
+/// A section of an ELF file with its name already resolved through the section header string table
+#[derive(Debug, Clone)]
+pub struct ElfSection {
+    pub name:       String,
+    pub stype:      u32,
+    pub flags:      u64,
+    pub addr:       usize,
+    pub offset:     usize,
+    pub size:       usize,
+    pub link:       u32,
+    pub info:       u32,
+    pub addralign:  usize,
+    pub entsize:    usize,
+}
+
+impl ElfSection {
+
+    /// returns true if this section occupies memory while the program runs
+    pub fn is_alloc(&self) -> bool {
+        (self.flags & SHF_ALLOC) != 0
+    }
+
+    /// returns the range of file offsets that hold the contents of this section. SHT_NOBITS sections
+    /// (such as .bss) do not occupy any space in the file
+    pub fn file_range(&self) -> std::ops::Range<usize> {
+        if self.stype == SHT_NOBITS {
+            self.offset..self.offset
+        } else {
+            self.offset..self.offset.saturating_add(self.size)
+        }
+    }
+}
+
 /// Represents an ELF Header
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -83,67 +308,157 @@ struct ElfHdr {
 impl ElfHdr {
 
     /// Takes in a raw u8 buffer of the ELF file to parse and performs checks on it
-    pub fn parse(buffer: &[u8]) -> Self {
+    pub fn parse(buffer: &[u8]) -> Result<Self, ElfError> {
 
         // Verify that the buffer is big enough to contain a header
-        assert!(SIZE_OF_ELF_HDR <= buffer.len(), "the file is too small to contain an ELF header");
+        if buffer.len() < SIZE_OF_ELF_HDR {
+            return Err(ElfError::TruncatedHeader(buffer.len()));
+        }
         
         // Copy the buffer into a sized array, otherwise rustc will complain
         let mut buffer_clone: [u8; SIZE_OF_ELF_HDR] = [0; SIZE_OF_ELF_HDR];
         buffer_clone.copy_from_slice(&buffer[..SIZE_OF_ELF_HDR]);
 
         // now transmute the buffer into an ELF struct and return it
-        unsafe {
-            std::mem::transmute(buffer_clone)
-        }
+        Ok(unsafe {
+            std::mem::transmute::<[u8; SIZE_OF_ELF_HDR], ElfHdr>(buffer_clone)
+        })
     }
 
-    /// Throws assertions incase of anything being off (not an ELF header, not an executable, incompatible architecture etc)
-    pub fn verify(&self) {
+    /// Returns an error incase of anything being off (not an ELF header, not an executable, incompatible architecture etc)
+    pub fn verify(&self) -> Result<(), ElfError> {
         
         // check the ELF header
-        assert!(u32::from_be(self.magic) == 0x7f454c46, "No ELF magic header was found in the target file");
+        if u32::from_be(self.magic) != 0x7f454c46 {
+            return Err(ElfError::BadMagic(u32::from_be(self.magic)));
+        }
 
         // ensure that this is a 64 bit binary
-        assert!(self.class == CLASS_64_BIT, "At this point, only 64b-it ELF are supported! :(");
+        if self.class != CLASS_64_BIT {
+            return Err(ElfError::WrongClass(self.class));
+        }
 
         // ensure that this is either an ELF_EXEC or ELF_DYN
-        assert!(self.etype == ELF_EXEC || self.etype == ELF_DYN, "At this point, only statically linked executables are supported :(");
+        if self.etype != ELF_EXEC && self.etype != ELF_DYN {
+            return Err(ElfError::UnsupportedType(self.etype));
+        }
 
         // ensure this ELF is for a supported OS
-        assert!(self.os_abi == LINUX_ABI || self.os_abi == SYSTEMV_ABI, "At this point, only the Linux and System-V ABIs are supported :(");
+        if self.os_abi != LINUX_ABI && self.os_abi != SYSTEMV_ABI {
+            return Err(ElfError::UnsupportedAbi(self.os_abi));
+        }
 
         // ensure the architecture is x86
-        assert!(self.machine == X86_MACHINE || self.machine == AMD64_MACHINE, "At this point, only x86-64 ELF's are supported :(");
+        if self.machine != X86_MACHINE && self.machine != AMD64_MACHINE {
+            return Err(ElfError::UnsupportedMachine(self.machine));
+        }
 
         // ensure that the program header size is standardized. We don't have time for some fancy non-standard ELFs
-        assert!(self.pheader_size == SIZE_OF_PROGRAM_HDR, "This ELF binary's Program Header Entry size differs from the standard Elf64_Phdr size :(");
+        if self.pheader_size != SIZE_OF_PROGRAM_HDR {
+            return Err(ElfError::BadPhentSize(self.pheader_size));
+        }
+
+        Ok(())
     }
 
-    /// Parse all PT_LOAD segments into a Vector ElfSegment's. These structs are used by the actual loader to
-    /// load the ELF and start it! Also, return the file path of the ELF interpreter used by this application
-    pub fn parse_segments(&self, buffer: &[u8]) -> (Option<String>, Vec<ElfSegment>) {
+    /// Parse the entire program header table, including headers the loader itself does not use
+    pub fn parse_program_headers(&self, buffer: &[u8]) -> Result<Vec<Elf64Phdr>, ElfError> {
         let mut current_offset = self.program_headers as usize;
 
         // verify that the current offset + all program headers are in bounds of the buffer representing the ELF file
-        let max_offset = current_offset.checked_add((self.pheader_num * self.pheader_size) as usize).unwrap() as usize;
-        assert!((max_offset) < buffer.len());
+        let out_of_bounds = ElfError::PhdrOutOfBounds { offset: self.program_headers, num: self.pheader_num };
+        // the size of the table is computed in usize, as the product of two u16 may overflow
+        let max_offset = match current_offset.checked_add(self.pheader_num as usize * self.pheader_size as usize) {
+            Some(max_offset) if max_offset <= buffer.len() => max_offset,
+            _ => return Err(out_of_bounds),
+        };
 
-        let mut elf_interp: Option<String> = None;
-        let mut res: Vec<ElfSegment> = Vec::new();
+        let mut res: Vec<Elf64Phdr> = Vec::new();
 
-        // iterate over each of the program headers and use transmute to get a parsed program header, which in turn will be 
-        // turned into a nice and safe Rust struct
+        // iterate over each of the program headers and use transmute to get a parsed program header
         while current_offset < max_offset {
             // Copy the current slice of the buffer into a fixed size array with the size of a program header
             let mut buffer_clone: [u8; SIZE_OF_PROGRAM_HDR as usize] = [0; SIZE_OF_PROGRAM_HDR as usize];
             buffer_clone.copy_from_slice(&buffer[current_offset..current_offset + SIZE_OF_PROGRAM_HDR as usize]);
 
             // then transmute
-            let program_header: Elf64Phdr = unsafe {
-                std::mem::transmute(buffer_clone)
-            };
+            res.push(unsafe {
+                std::mem::transmute::<[u8; SIZE_OF_PROGRAM_HDR as usize], Elf64Phdr>(buffer_clone)
+            });
 
+            current_offset += SIZE_OF_PROGRAM_HDR as usize;
+        }
+
+        Ok(res)
+    }
+
+    /// Performs the same checks on the PT_LOAD segments the kernel's fs/binfmt_elf.c performs before mapping
+    /// anything, so that the loader can rely on a sane layout
+    pub fn validate_segments(&self, program_headers: &[Elf64Phdr]) -> Result<(), ElfError> {
+        let mut prev: Option<&Elf64Phdr> = None;
+        let mut entry_found = false;
+
+        for phdr in program_headers.iter().filter(|phdr| phdr.ptype == PT_LOAD) {
+            let (vaddr, offset, filesz, memsz, align) = (phdr.vaddr, phdr.offset, phdr.filesz, phdr.memsz, phdr.align);
+
+            if filesz > memsz {
+                return Err(ElfError::FileszExceedsMemsz { vaddr, filesz, memsz });
+            }
+
+            // the kernel refuses segments that reach beyond TASK_SIZE, this includes the ones that wrap around
+            if memsz > TASK_SIZE as u64 || TASK_SIZE as u64 - memsz < vaddr {
+                return Err(ElfError::SegmentWrapsAround { vaddr, memsz });
+            }
+
+            // an alignment of 0 or 1 means that there are no alignment constraints
+            if align > 1 {
+                if !align.is_power_of_two() {
+                    return Err(ElfError::BadSegmentAlignment { vaddr, align });
+                }
+                if offset % align != vaddr % align {
+                    return Err(ElfError::MisalignedSegment { vaddr, offset, align });
+                }
+            }
+
+            if let Some(prev) = prev {
+                let (prev_vaddr, prev_end) = (prev.vaddr, prev.vaddr + prev.memsz);
+                if vaddr < prev_vaddr {
+                    return Err(ElfError::UnsortedSegments { vaddr, prev_vaddr });
+                }
+                if vaddr < prev_end {
+                    return Err(ElfError::OverlappingSegments { vaddr, prev_end });
+                }
+            }
+
+            if (phdr.pflags & PF_X) != 0 && self.entry_point >= vaddr && self.entry_point - vaddr < memsz {
+                entry_found = true;
+            }
+
+            prev = Some(phdr);
+        }
+
+        if prev.is_none() {
+            return Err(ElfError::NoLoadSegments);
+        }
+
+        // shared libraries that are not meant to be started have no entry point at all. They still have to parse,
+        // so that their sections, symbols and dynamic section can be inspected
+        let library = self.etype == ELF_DYN && self.entry_point == 0;
+        if !entry_found && !library {
+            return Err(ElfError::EntryNotExecutable(self.entry_point));
+        }
+
+        Ok(())
+    }
+
+    /// Parse all PT_LOAD segments into a Vector ElfSegment's. These structs are used by the actual loader to
+    /// load the ELF and start it! Also, return the file path of the ELF interpreter used by this application
+    pub fn parse_segments(&self, buffer: &[u8], program_headers: &[Elf64Phdr]) -> Result<(Option<String>, Vec<ElfSegment>), ElfError> {
+        let mut elf_interp: Option<String> = None;
+        let mut res: Vec<ElfSegment> = Vec::new();
+
+        // turn each loadable program header into a nice and safe Rust struct
+        for program_header in program_headers.iter() {
 
             // Only parse this segment if it is loadable or an ELF interpreter
             if program_header.ptype == PT_LOAD || program_header.ptype == PT_INTERP {
@@ -151,44 +466,194 @@ impl ElfHdr {
                 // otherwise interpret the contents of the section as a String that contains the path to the ELF interpreter 
                 // of this file
                 if program_header.ptype == PT_LOAD {
-                    let offset = program_header.offset as usize - (program_header.vaddr as usize & (0x1000 -1));
-                    let end_offset = offset.checked_add(program_header.filesz as usize + (program_header.vaddr as usize & (0x1000 -1))).unwrap();
-                    assert!(end_offset < buffer.len());
+                    let out_of_bounds = ElfError::SegmentOutOfBounds { offset: program_header.offset, filesz: program_header.filesz };
+                    let page_offset = program_header.vaddr as usize & (0x1000 -1);
+                    let offset = match (program_header.offset as usize).checked_sub(page_offset) {
+                        Some(offset) => offset,
+                        None => return Err(out_of_bounds),
+                    };
+                    match offset.checked_add(program_header.filesz as usize).and_then(|end| end.checked_add(page_offset)) {
+                        Some(end_offset) if end_offset <= buffer.len() => {},
+                        _ => return Err(out_of_bounds),
+                    };
 
+                    // the contents are mapped straight from the file by the loader, so there is nothing to copy here
                     res.push(
-                        ElfSegment::new(&program_header, buffer[offset..end_offset].to_vec())
+                        ElfSegment::new(program_header)
                     );
                 } else {
                     // if this is an interpreter segment, interpret the offset as "absolute" offset
                     // and read the filename (-1) since it contains a NULL byte that RUST does not want to deal
                     // with
                     let offset = program_header.offset as usize;
+                    let interp = match offset.checked_add(program_header.filesz as usize) {
+                        Some(end_offset) if program_header.filesz >= 2 && end_offset <= buffer.len() => &buffer[offset..end_offset],
+                        _ => return Err(ElfError::BadInterp),
+                    };
+                    if interp[interp.len() - 1] != 0 {
+                        return Err(ElfError::BadInterp);
+                    }
                     elf_interp = Some(
-                        String::from_utf8(buffer[offset..(offset + program_header.filesz as usize - 1)].to_vec()).expect("INTERP segment contains invalid filename")
+                        String::from_utf8(interp[..interp.len() - 1].to_vec()).map_err(|_| ElfError::BadInterp)?
                     );
                 }
 
             }
+        }
 
-            current_offset += SIZE_OF_PROGRAM_HDR as usize;
+        Ok((elf_interp, res))
+    }
+
+    /// Parse the section header table and resolve the name of each section through the section header
+    /// string table. Stripped files without a section header table simply have no sections and the sections of
+    /// files without a section header string table (e_shstrndx is SHN_UNDEF) have no names.
+    pub fn parse_sections(&self, buffer: &[u8]) -> Result<Vec<ElfSection>, ElfError> {
+        if self.section_table_off == 0 {
+            return Ok(Vec::new());
+        }
+
+        if self.shent_size != SIZE_OF_SECTION_HDR {
+            return Err(ElfError::BadShentSize(self.shent_size));
         }
 
-        (elf_interp, res)
+        let table_off = self.section_table_off as usize;
+        let read_shdr = |idx: usize| -> Option<Elf64Shdr> {
+            let start = idx.checked_mul(SIZE_OF_SECTION_HDR as usize)?.checked_add(table_off)?;
+            let end = start.checked_add(SIZE_OF_SECTION_HDR as usize)?;
+            let mut buffer_clone: [u8; SIZE_OF_SECTION_HDR as usize] = [0; SIZE_OF_SECTION_HDR as usize];
+            buffer_clone.copy_from_slice(buffer.get(start..end)?);
+
+            Some(unsafe {
+                std::mem::transmute::<[u8; SIZE_OF_SECTION_HDR as usize], Elf64Shdr>(buffer_clone)
+            })
+        };
+
+        // if there are too many sections to fit into e_shnum or e_shstrndx, the real values are stored
+        // in the sh_size and sh_link fields of the initial (SHN_UNDEF) section header
+        let first = read_shdr(0).ok_or(ElfError::ShdrOutOfBounds { offset: self.section_table_off, num: 1 })?;
+        let shnum = if self.shnum == 0 { first.size as usize } else { self.shnum as usize };
+        let shstrndx = if self.shstrnidx == SHN_XINDEX { first.link as usize } else { self.shstrnidx as usize };
+
+        let headers = (0..shnum)
+            .map(read_shdr)
+            .collect::<Option<Vec<Elf64Shdr>>>()
+            .ok_or(ElfError::ShdrOutOfBounds { offset: self.section_table_off, num: shnum })?;
+
+        // locate the string table holding the section names
+        let strtab = match headers.get(shstrndx) {
+            _ if shstrndx == SHN_UNDEF => None,
+            Some(hdr) if hdr.stype == SHT_STRTAB => {
+                let start = hdr.offset as usize;
+                let strtab = start.checked_add(hdr.size as usize)
+                    .and_then(|end| buffer.get(start..end))
+                    .ok_or(ElfError::BadShstrndx(shstrndx))?;
+                Some(strtab)
+            },
+            _ => return Err(ElfError::BadShstrndx(shstrndx)),
+        };
+
+        headers.iter().map(|hdr| {
+            let name = match strtab {
+                Some(strtab) => strtab.get(hdr.name as usize..)
+                    .and_then(|name| name.iter().position(|&c| c == 0).map(|len| &name[..len]))
+                    .ok_or(ElfError::BadSectionName(hdr.name))?,
+                None => &[],
+            };
+
+            Ok(ElfSection {
+                name: String::from_utf8_lossy(name).into_owned(),
+                stype: hdr.stype,
+                flags: hdr.flags,
+                addr: hdr.addr as usize,
+                offset: hdr.offset as usize,
+                size: hdr.size as usize,
+                link: hdr.link,
+                info: hdr.info,
+                addralign: hdr.addralign as usize,
+                entsize: hdr.entsize as usize,
+            })
+        }).collect()
     }
 }
 
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ElfType {
     ElfExec,
     ElfDyn
 }
 
 impl ElfType {
-    pub fn from(etype: u16) -> Self {
+    pub fn from(etype: u16) -> Result<Self, ElfError> {
         match etype {
-            ELF_EXEC => ElfType::ElfExec,
-            ELF_DYN => ElfType::ElfDyn,
-            _ => panic!("Invalid ELF type")
+            ELF_EXEC => Ok(ElfType::ElfExec),
+            ELF_DYN => Ok(ElfType::ElfDyn),
+            _ => Err(ElfError::UnsupportedType(etype))
+        }
+    }
+}
+
+/// What an ELF asks for its stack, mirrors the kernel's EXSTACK_* values
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExecStack {
+    /// there is no PT_GNU_STACK header, which is the case for legacy binaries
+    Default,
+    /// PT_GNU_STACK is present and does not have PF_X set
+    Disabled,
+    /// PT_GNU_STACK is present and requests an executable stack
+    Enabled,
+}
+
+/// A read-only, private mapping of an entire ELF file. Parsing works on this mapping so that the
+/// file never has to be copied into memory, the pages are shared with the page cache instead
+pub struct FileMapping {
+    addr: usize,
+    len: usize,
+}
+
+impl FileMapping {
+
+    /// maps the entire file into memory
+    pub fn map(file: &File) -> Result<Self, ElfError> {
+        let len = file.metadata()?.len() as usize;
+
+        // mmap() refuses to create empty mappings
+        if len == 0 {
+            return Ok(FileMapping { addr: 0, len: 0 });
+        }
+
+        let addr = unsafe {
+            mmap(std::ptr::null_mut(), len, ProtFlags::PROT_READ, MapFlags::MAP_PRIVATE, file.as_raw_fd(), 0)
+                .map_err(|err| std::io::Error::from_raw_os_error(err.as_errno().map_or(libc::EINVAL, |errno| errno as i32)))?
+        };
+
+        Ok(FileMapping {
+            addr: addr as usize,
+            len,
+        })
+    }
+}
+
+impl Deref for FileMapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+
+        unsafe {
+            std::slice::from_raw_parts(self.addr as *const u8, self.len)
+        }
+    }
+}
+
+impl Drop for FileMapping {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe {
+                let _ = munmap(self.addr as *mut c_void, self.len);
+            }
         }
     }
 }
@@ -201,37 +666,402 @@ pub struct LoadInfo {
     pub segments: Vec<ElfSegment>,
     pub elf_interp: Option<String>,
     pub etype: ElfType,
+    /// the sections of the file, empty when the section header table could not be parsed
+    pub sections: Vec<ElfSection>,
+    /// why the section header table could not be parsed. Broken section headers never fail a load, as the kernel
+    /// ignores them as well, but tooling that relies on the sections can report this
+    pub section_error: Option<ElfError>,
+    /// every program header of the file, including the ones that are not loaded
+    pub program_headers: Vec<Elf64Phdr>,
+    pub exec_stack: ExecStack,
+    /// the raw contents of the ELF file, used to lazily parse tables such as the symbol tables
+    pub data: FileMapping,
+    /// the ELF file itself, the loader maps the segments straight from it
+    pub file: File,
+}
+
+impl LoadInfo {
+
+    /// returns the first program header of the given type, e.g. PT_DYNAMIC
+    pub fn program_header(&self, ptype: u32) -> Option<&Elf64Phdr> {
+        self.program_headers.iter().find(|phdr| phdr.ptype == ptype)
+    }
+
+    /// translates a virtual address of the image into an offset into the file, using the PT_LOAD segment
+    /// that maps it. Returns None for addresses that are not backed by the file (e.g. .bss)
+    pub fn vaddr_to_offset(&self, vaddr: usize) -> Option<usize> {
+        self.program_headers.iter()
+            .filter(|phdr| phdr.ptype == PT_LOAD)
+            .find(|phdr| vaddr >= phdr.vaddr as usize && vaddr - (phdr.vaddr as usize) < phdr.filesz as usize)
+            .map(|phdr| vaddr - phdr.vaddr as usize + phdr.offset as usize)
+    }
+
+    /// returns the first section with the given name, e.g. ".text" or ".dynamic"
+    pub fn section(&self, name: &str) -> Option<&ElfSection> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// returns all sections of the given type, e.g. SHT_SYMTAB
+    pub fn sections_of_type(&self, stype: u32) -> impl Iterator<Item = &ElfSection> {
+        self.sections.iter().filter(move |section| section.stype == stype)
+    }
+
+    /// returns the contents of a section as found in the file or None if they lie outside of the file
+    pub fn section_data(&self, section: &ElfSection) -> Option<&[u8]> {
+        self.data.get(section.file_range())
+    }
 }
 
 
 
 /// Parses an ELF file and performs checks on it, such as verify the architecture, that is an executable and that it is 64bit.
 /// It then returns all necessary information needed by the loader (entry point and LOAD segments)
-pub fn parse_elf(file: &str) -> LoadInfo {
-    let mut elf_file = File::open(file).expect("Could not find file");
-    
-    // read the file into a dynamic sized buffer
-    let mut buffer = Vec::new();
-    elf_file.read_to_end(&mut buffer).expect("Could not read ELF file!");
-    let buffer = buffer;
+pub fn parse_elf<P: AsRef<Path>>(file: P) -> Result<LoadInfo, ElfError> {
+    parse_elf_file(File::open(file)?)
+}
+
+/// Parses an ELF from a file descriptor, such as a memfd. The descriptor is duplicated, so the caller keeps ownership of it
+pub fn parse_elf_fd(fd: RawFd) -> Result<LoadInfo, ElfError> {
+    let dup_fd = unsafe {
+        libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0)
+    };
+    if dup_fd < 0 {
+        return Err(ElfError::Io(std::io::Error::last_os_error()));
+    }
+
+    parse_elf_file(unsafe { File::from_raw_fd(dup_fd) })
+}
+
+/// Parses an ELF image that only exists in memory. The image is copied into an anonymous memfd, so that
+/// the loader can map the segments from it just like from a file on disk
+pub fn parse_elf_bytes(image: &[u8]) -> Result<LoadInfo, ElfError> {
+    let mut memfd = create_memfd()?;
+    memfd.write_all(image)?;
+    parse_elf_file(memfd)
+}
+
+/// Parses an ELF image that is read from a stream such as stdin. The image never touches the filesystem,
+/// it is read into an anonymous memfd instead
+pub fn parse_elf_reader<R: Read>(reader: &mut R) -> Result<LoadInfo, ElfError> {
+    let mut memfd = create_memfd()?;
+    std::io::copy(reader, &mut memfd)?;
+    parse_elf_file(memfd)
+}
+
+/// creates an anonymous, memory backed file
+fn create_memfd() -> Result<File, ElfError> {
+    let fd = unsafe {
+        libc::memfd_create("userspace_loader\0".as_ptr() as *const libc::c_char, libc::MFD_CLOEXEC)
+    };
+    if fd < 0 {
+        return Err(ElfError::Io(std::io::Error::last_os_error()));
+    }
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Parses an already opened ELF file, see parse_elf()
+pub fn parse_elf_file(elf_file: File) -> Result<LoadInfo, ElfError> {
+    // map the file instead of reading it, so that no copy of it has to be made
+    let buffer = FileMapping::map(&elf_file)?;
 
     // make sure this is a valid ELF and prepare to parse
-    let hdr = ElfHdr::parse(&buffer);
-    hdr.verify();
+    let hdr = ElfHdr::parse(&buffer)?;
+    hdr.verify()?;
 
 
     // parse all the loadable segments
 
     // parse the segments and pass them to the loader, as well as all necessary information
     //(hdr.program_headers as usize, hdr.entry_point as usize, hdr.parse_segments(&buffer))
-    let (elf_interp, segments) = hdr.parse_segments(&buffer);
-    LoadInfo {
+    let program_headers = hdr.parse_program_headers(&buffer)?;
+    hdr.validate_segments(&program_headers)?;
+    let (elf_interp, segments) = hdr.parse_segments(&buffer, &program_headers)?;
+
+    // the last PT_GNU_STACK header decides wether the stack is executable, just like in the kernel
+    let exec_stack = match program_headers.iter().rev().find(|phdr| phdr.ptype == PT_GNU_STACK) {
+        Some(phdr) if (phdr.pflags & PF_X) != 0 => ExecStack::Enabled,
+        Some(_) => ExecStack::Disabled,
+        None => ExecStack::Default,
+    };
+
+    // parse the section headers so that tooling can locate sections such as .text or .dynamic. The kernel never
+    // looks at them and stripped or packed programs often have broken ones, so such a file simply has no sections
+    // and the error is kept around for whoever wants to know
+    let (sections, section_error) = match hdr.parse_sections(&buffer) {
+        Ok(sections) => (sections, None),
+        Err(error) => (Vec::new(), Some(error)),
+    };
+    Ok(LoadInfo {
         entry_point: hdr.entry_point as usize,
         pheader_off: hdr.program_headers as usize,
         pheader_num: hdr.pheader_num as usize,
-        segments: segments,
-        elf_interp: elf_interp,
-        etype: ElfType::from(hdr.etype)
-    }
+        segments,
+        elf_interp,
+        etype: ElfType::from(hdr.etype)?,
+        sections,
+        section_error,
+        program_headers,
+        exec_stack,
+        data: buffer,
+        file: elf_file,
+    })
 }
 
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// offsets of the e_type, e_entry, e_shoff and e_shstrndx fields in the ELF header
+    const E_TYPE: usize = 16;
+    const E_ENTRY: usize = 24;
+    const E_SHOFF: usize = 40;
+    const E_SHSTRNDX: usize = 62;
+
+    /// where the section header string table and the section header table are placed
+    const SHSTRTAB_OFF: usize = 0x200;
+    const SHDR_OFF: usize = 0x240;
+
+    const IMAGE_SIZE: usize = 0x1000;
+    pub(crate) const BASE: u64 = 0x400000;
+    const ENTRY: u64 = BASE + 0x100;
+
+    /// a readable and executable PT_LOAD segment that maps the whole image at BASE
+    pub(crate) fn load_segment() -> Elf64Phdr {
+        Elf64Phdr {
+            ptype: PT_LOAD,
+            pflags: 5,
+            offset: 0,
+            vaddr: BASE,
+            paddr: BASE,
+            filesz: IMAGE_SIZE as u64,
+            memsz: IMAGE_SIZE as u64,
+            align: 0x1000,
+        }
+    }
+
+    pub(crate) fn put(image: &mut [u8], offset: usize, data: &[u8]) {
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// builds an ET_EXEC image with the given program headers, followed by the sections .text and .shstrtab
+    pub(crate) fn elf_image(phdrs: &[Elf64Phdr]) -> Vec<u8> {
+        let mut image = vec![0u8; IMAGE_SIZE];
+
+        put(&mut image, 0, &[0x7f, b'E', b'L', b'F', CLASS_64_BIT, 1, 1, SYSTEMV_ABI]);
+        put(&mut image, E_TYPE, &ELF_EXEC.to_le_bytes());
+        put(&mut image, 18, &AMD64_MACHINE.to_le_bytes());
+        put(&mut image, 20, &1u32.to_le_bytes());
+        put(&mut image, E_ENTRY, &ENTRY.to_le_bytes());
+        put(&mut image, 32, &(SIZE_OF_ELF_HDR as u64).to_le_bytes());
+        put(&mut image, E_SHOFF, &(SHDR_OFF as u64).to_le_bytes());
+        put(&mut image, 52, &(SIZE_OF_ELF_HDR as u16).to_le_bytes());
+        put(&mut image, 54, &SIZE_OF_PROGRAM_HDR.to_le_bytes());
+        put(&mut image, 56, &(phdrs.len() as u16).to_le_bytes());
+        put(&mut image, 58, &SIZE_OF_SECTION_HDR.to_le_bytes());
+        put(&mut image, 60, &3u16.to_le_bytes());
+        put(&mut image, E_SHSTRNDX, &2u16.to_le_bytes());
+
+        for (idx, phdr) in phdrs.iter().enumerate() {
+            let bytes = unsafe {
+                std::mem::transmute::<Elf64Phdr, [u8; SIZE_OF_PROGRAM_HDR as usize]>(*phdr)
+            };
+            put(&mut image, SIZE_OF_ELF_HDR + idx * SIZE_OF_PROGRAM_HDR as usize, &bytes);
+        }
+
+        let shstrtab = b"\0.text\0.shstrtab\0";
+        put(&mut image, SHSTRTAB_OFF, shstrtab);
+
+        let text = Elf64Shdr { name: 1, stype: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR, addr: ENTRY, offset: 0x100, size: 0x100, link: 0, info: 0, addralign: 16, entsize: 0 };
+        let strtab = Elf64Shdr { name: 7, stype: SHT_STRTAB, flags: 0, addr: 0, offset: SHSTRTAB_OFF as u64, size: shstrtab.len() as u64, link: 0, info: 0, addralign: 1, entsize: 0 };
+        for (idx, shdr) in [text, strtab].iter().enumerate() {
+            let bytes = unsafe {
+                std::mem::transmute::<Elf64Shdr, [u8; SIZE_OF_SECTION_HDR as usize]>(*shdr)
+            };
+            put(&mut image, SHDR_OFF + (idx + 1) * SIZE_OF_SECTION_HDR as usize, &bytes);
+        }
+
+        image
+    }
+
+    /// a section that elf_image_with_sections() adds to the image
+    pub(crate) struct TestSection<'a> {
+        pub name: &'a str,
+        pub stype: u32,
+        /// the index of a linked section, the first added section has index 3
+        pub link: u32,
+        pub entsize: u64,
+        pub data: &'a [u8],
+    }
+
+    /// where elf_image_with_sections() places the contents of the added sections and the section names
+    const SECTION_DATA_OFF: usize = 0x400;
+    const SECTION_NAMES_OFF: usize = 0xc00;
+
+    /// builds the same image as elf_image() with up to four more sections after .text and .shstrtab
+    pub(crate) fn elf_image_with_sections(phdrs: &[Elf64Phdr], sections: &[TestSection]) -> Vec<u8> {
+        let mut image = elf_image(phdrs);
+        let mut names = b"\0.text\0.shstrtab\0".to_vec();
+        let mut data_off = SECTION_DATA_OFF;
+
+        for (idx, section) in sections.iter().enumerate() {
+            let shdr = Elf64Shdr {
+                name: names.len() as u32,
+                stype: section.stype,
+                flags: 0,
+                addr: 0,
+                offset: data_off as u64,
+                size: section.data.len() as u64,
+                link: section.link,
+                info: 0,
+                addralign: 8,
+                entsize: section.entsize,
+            };
+            let bytes = unsafe {
+                std::mem::transmute::<Elf64Shdr, [u8; SIZE_OF_SECTION_HDR as usize]>(shdr)
+            };
+            put(&mut image, SHDR_OFF + (idx + 3) * SIZE_OF_SECTION_HDR as usize, &bytes);
+            put(&mut image, data_off, section.data);
+
+            names.extend_from_slice(section.name.as_bytes());
+            names.push(0);
+            data_off = (data_off + section.data.len() + 7) & !7;
+        }
+        assert!(SHDR_OFF + (sections.len() + 3) * SIZE_OF_SECTION_HDR as usize <= SECTION_DATA_OFF && data_off <= SECTION_NAMES_OFF);
+
+        // the section names no longer fit in front of the section header table, so .shstrtab moves
+        put(&mut image, SECTION_NAMES_OFF, &names);
+        put(&mut image, SHDR_OFF + 2 * SIZE_OF_SECTION_HDR as usize + 24, &(SECTION_NAMES_OFF as u64).to_le_bytes());
+        put(&mut image, SHDR_OFF + 2 * SIZE_OF_SECTION_HDR as usize + 32, &(names.len() as u64).to_le_bytes());
+        put(&mut image, 60, &(sections.len() as u16 + 3).to_le_bytes());
+        image
+    }
+
+    /// the same image as elf_image(), but position independent (ET_DYN)
+    pub(crate) fn dyn_image(phdrs: &[Elf64Phdr]) -> Vec<u8> {
+        let mut image = elf_image(phdrs);
+        put(&mut image, E_TYPE, &ELF_DYN.to_le_bytes());
+        image
+    }
+
+    fn section_names(load_info: &LoadInfo) -> Vec<&str> {
+        load_info.sections.iter().map(|section| section.name.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_sections() {
+        let load_info = parse_elf_bytes(&elf_image(&[load_segment()])).unwrap();
+        assert_eq!(section_names(&load_info), ["", ".text", ".shstrtab"]);
+        assert_eq!(load_info.section(".text").unwrap().addr, ENTRY as usize);
+        assert!(load_info.section_error.is_none());
+    }
+
+    #[test]
+    fn section_table_outside_of_the_file_is_ignored() {
+        let mut image = elf_image(&[load_segment()]);
+        put(&mut image, E_SHOFF, &0xffffffu64.to_le_bytes());
+
+        let load_info = parse_elf_bytes(&image).unwrap();
+        assert!(load_info.sections.is_empty());
+        assert!(matches!(load_info.section_error, Some(ElfError::ShdrOutOfBounds { offset: 0xffffff, num: 1 })));
+        assert_eq!(load_info.segments.len(), 1);
+    }
+
+    #[test]
+    fn sections_without_string_table_have_no_names() {
+        let mut image = elf_image(&[load_segment()]);
+        put(&mut image, E_SHSTRNDX, &0u16.to_le_bytes());
+
+        let load_info = parse_elf_bytes(&image).unwrap();
+        assert_eq!(section_names(&load_info), ["", "", ""]);
+        assert_eq!(load_info.sections[1].stype, SHT_PROGBITS);
+    }
+
+    /// the same image at BASE split into a read only segment and an executable one
+    fn two_segments() -> [Elf64Phdr; 2] {
+        let first = Elf64Phdr { pflags: 4, filesz: 0x100, memsz: 0x100, ..load_segment() };
+        let second = Elf64Phdr { offset: 0x100, vaddr: BASE + 0x100, paddr: BASE + 0x100, filesz: 0xf00, memsz: 0xf00, ..load_segment() };
+        [first, second]
+    }
+
+    fn parse_segments(phdrs: &[Elf64Phdr]) -> Result<LoadInfo, ElfError> {
+        parse_elf_bytes(&elf_image(phdrs))
+    }
+
+    #[test]
+    fn valid_segments_pass() {
+        assert_eq!(parse_segments(&[load_segment()]).unwrap().segments.len(), 1);
+        assert_eq!(parse_segments(&two_segments()).unwrap().segments.len(), 2);
+
+        // .bss makes memsz larger than filesz and an alignment of 0 means no constraints
+        let bss = Elf64Phdr { memsz: 0x3000, align: 0, ..load_segment() };
+        assert!(parse_segments(&[bss]).is_ok());
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let [first, mut second] = two_segments();
+        second.vaddr = BASE + 0x80;
+        second.offset = 0x80;
+        second.align = 0x10;
+        assert!(matches!(parse_segments(&[first, second]), Err(ElfError::OverlappingSegments { .. })));
+    }
+
+    #[test]
+    fn rejects_unsorted_segments() {
+        let [first, second] = two_segments();
+        assert!(matches!(parse_segments(&[second, first]), Err(ElfError::UnsortedSegments { .. })));
+    }
+
+    #[test]
+    fn rejects_misaligned_segments() {
+        let misaligned = Elf64Phdr { offset: 0x10, filesz: 0xff0, ..load_segment() };
+        assert!(matches!(parse_segments(&[misaligned]), Err(ElfError::MisalignedSegment { .. })));
+
+        let bad_align = Elf64Phdr { align: 0x1800, ..load_segment() };
+        assert!(matches!(parse_segments(&[bad_align]), Err(ElfError::BadSegmentAlignment { .. })));
+    }
+
+    #[test]
+    fn rejects_segments_outside_of_the_file() {
+        let beyond = Elf64Phdr { filesz: 0x2000, memsz: 0x2000, ..load_segment() };
+        assert!(matches!(parse_segments(&[beyond]), Err(ElfError::SegmentOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn rejects_oversized_segments() {
+        let filesz = Elf64Phdr { memsz: 0x800, ..load_segment() };
+        assert!(matches!(parse_segments(&[filesz]), Err(ElfError::FileszExceedsMemsz { .. })));
+
+        let wraps = Elf64Phdr { memsz: u64::MAX - 0xfff, ..load_segment() };
+        assert!(matches!(parse_segments(&[wraps]), Err(ElfError::SegmentWrapsAround { .. })));
+
+        let beyond_task_size = Elf64Phdr { vaddr: TASK_SIZE as u64 - 0x800, ..load_segment() };
+        assert!(matches!(parse_segments(&[beyond_task_size]), Err(ElfError::SegmentWrapsAround { .. })));
+    }
+
+    #[test]
+    fn rejects_images_without_executable_entry() {
+        assert!(matches!(parse_segments(&[]), Err(ElfError::NoLoadSegments)));
+
+        let not_executable = Elf64Phdr { pflags: 4, ..load_segment() };
+        assert!(matches!(parse_segments(&[not_executable]), Err(ElfError::EntryNotExecutable(ENTRY))));
+
+        let mut image = elf_image(&[not_executable]);
+        put(&mut image, E_ENTRY, &0u64.to_le_bytes());
+        assert!(matches!(parse_elf_bytes(&image), Err(ElfError::EntryNotExecutable(0))));
+    }
+
+    #[test]
+    fn shared_libraries_without_entry_point_parse() {
+        let library = Elf64Phdr { pflags: 4, vaddr: 0, paddr: 0, ..load_segment() };
+        let mut image = dyn_image(&[library]);
+        put(&mut image, E_ENTRY, &0u64.to_le_bytes());
+
+        let load_info = parse_elf_bytes(&image).unwrap();
+        assert_eq!(load_info.etype, ElfType::ElfDyn);
+        assert_eq!(load_info.entry_point, 0);
+        assert_eq!(load_info.sections.len(), 3);
+    }
+}
@@ -7,236 +7,702 @@ use rand::Rng;
 extern crate nix;
 use nix::sys::mman::{
     mmap,
+    mprotect,
     ProtFlags,
     MapFlags
 };
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 extern crate libc;
 
-use crate::parse_elf::{
-    LoadInfo,
-    ElfType
+use crate::auxv::{
+    kernel_auxv,
+    AuxOverride,
+    AT_NULL,
+    AT_EXECFD,
+    AT_PHDR,
+    AT_PHENT,
+    AT_PHNUM,
+    AT_BASE,
+    AT_FLAGS,
+    AT_ENTRY,
+    AT_PLATFORM,
+    AT_BASE_PLATFORM,
+    AT_RANDOM,
+    AT_EXECFN
 };
+use crate::load_elf::{map_fixed_noreplace, ElfLoad, TASK_SIZE};
+use crate::parse_elf::LoadInfo;
 
 
 
-/// ELFAux IDs and values
-const AT_SYSINFO_EHDR: u64 = 33;
-const AT_HWCAP: u64 =   16;
-const AT_PAGESZ: u64 =  6;
-const AT_CLKTCK: u64 =  17;
-const AT_PHDR: u64 =    3;
-const AT_PHENT: u64 =   4;
-const AT_PHNUM: u64 =   5;
-const AT_BASE: u64 =   7;
-const AT_FLAGS: u64 =   8;
-const AT_ENTRY: u64 =   9;
-const AT_UID: u64 =   11;
-const AT_EUID: u64 =   12;
-const AT_GID: u64 =   13;
-const AT_EGID: u64 =   14;
-const AT_SECURE: u64 =   23;
-const AT_RANDOM: u64 =   25;
-const AT_HWCAP2: u64 =   26;
-const AT_EXECFN: u64 =   31;
-const AT_NULL: u64 =   0;
-
-
 /// the standard size of a program header and the only one we support
 const PHENT_SIZE: usize = 0x38;
 
+/// the stack size the kernel uses if RLIMIT_STACK is unlimited (_STK_LIM)
+pub const DEFAULT_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// the highest address of the stack, STACK_TOP of the kernel
+const STACK_TOP: usize = TASK_SIZE;
+
+/// randomize_stack_top() moves the top of the stack down by up to 22 bits worth of pages (16GB)
+const STACK_RND_MASK: usize = 0x3fffff;
+
+/// the size of the inaccessible area below the stack, stack_guard_gap of the kernel
+const STACK_GUARD_GAP: usize = 256 * 0x1000;
+
+/// arch_align_stack() moves the stack pointer down by less than this many bytes
+const STACK_ALIGN_RND: usize = 8192;
+
+/// how often a random location for the stack is tried before mmap() gets to choose one
+const STACK_PLACEMENT_TRIES: usize = 8;
 
-/// sets up an initial stack according to the System-V x86 ABI and passes information such
-/// as the entry point and location of the program headers of the application to tbe loaded
-/// to the ELF Interpreter or the CSU routines.
-pub fn setup_stack(load_info: &LoadInfo, load_address: usize, interp_base: usize) -> usize {
-    // create a new stack for the application and set it up just like the kernel does
 
-    // first, allocate the new stack area and give it 256KB of memory (just a random value I chose)
+/// Everything that can go wrong while setting up the initial stack
+#[derive(Debug)]
+pub enum StackError {
+    /// the stack could not be mapped
+    Map(nix::Error),
+    /// the arguments, environment and aux vector do not fit onto the stack
+    TooLarge { needed: usize, size: usize },
+    /// data was to be aligned to a value that is not a power of two
+    BadAlignment(usize),
+    /// argv, envp or the aux vector refer to data that was pushed onto a different stack
+    ForeignRef(StackRef),
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackError::Map(err) => write!(f, "failed to map the stack: {}", err),
+            StackError::TooLarge { needed, size } => write!(f, "the arguments and environment need {:#x} bytes, but the stack is only {:#x} bytes large", needed, size),
+            StackError::BadAlignment(align) => write!(f, "the alignment {:#x} is not a power of two", align),
+            StackError::ForeignRef(_) => write!(f, "the stack refers to data that was pushed onto a different stack"),
+        }
+    }
+}
+
+impl std::error::Error for StackError {}
+
+
+/// describes what the loaded program gets to see on its initial stack
+pub struct StackConfig {
+    /// the arguments of the loaded program, argv[0] included
+    pub args: Vec<OsString>,
+    /// the environment of the loaded program as NAME=VALUE strings
+    pub env: Vec<OsString>,
+    /// the file name of the program as it was passed to the loader, AT_EXECFN points to it
+    pub execfn: OsString,
+    /// map the stack with PROT_EXEC, as requested by PT_GNU_STACK or implied by READ_IMPLIES_EXEC
+    pub executable: bool,
+    /// changes to the aux vector, applied on top of the one the kernel would pass
+    pub auxv_overrides: Vec<AuxOverride>,
+    /// the 16 bytes AT_RANDOM points to, random ones are used if None
+    pub random_seed: Option<[u8; 16]>,
+    /// the size of the stack, not including the guard gap below it
+    pub size: usize,
+}
+
+/// The stack setup_stack() created
+pub struct NewStack {
+    /// the initial stack pointer, it points to argc
+    pub rsp: usize,
+    /// the lowest address of the stack mapping, including the guard gap
+    pub start: usize,
+    /// the top of the stack
+    pub end: usize,
+    /// what was written onto the stack and where
+    pub layout: StackLayout,
+}
+
+impl StackConfig {
+    /// returns the stack size a real process would get: RLIMIT_STACK, or _STK_LIM if that is unlimited
+    pub fn default_size() -> usize {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        let res = unsafe {
+            libc::getrlimit(libc::RLIMIT_STACK, &mut limit)
+        };
+
+        if res != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+            DEFAULT_STACK_SIZE
+        } else {
+            limit.rlim_cur as usize
+        }
+    }
+}
+
+/// A reference to data pushed onto an InitialStack. It resolves to the address of that data once the stack is
+/// laid out, so that argv, envp and the aux vector can point to it. It is only valid for the stack it was
+/// pushed onto and the clones made of it afterwards
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StackRef {
+    stack: usize,
+    idx: usize,
+}
+
+/// A pointer sized entry of argv, envp or the aux vector
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StackValue {
+    /// a fixed value, e.g. the entry point or a pointer into memory outside of the stack
+    Value(usize),
+    /// the address of data pushed onto the same stack
+    Ref(StackRef),
+}
+
+impl From<usize> for StackValue {
+    fn from(value: usize) -> Self {
+        StackValue::Value(value)
+    }
+}
+
+impl From<StackRef> for StackValue {
+    fn from(stack_ref: StackRef) -> Self {
+        StackValue::Ref(stack_ref)
+    }
+}
+
+/// an element of the data area at the top of the stack
+#[derive(Debug, Clone)]
+enum StackItem {
+    Data(Vec<u8>),
+    Pad(usize),
+    Align(usize),
+}
+
+/// Builds an initial process stack as described by the System-V x86-64 ABI. The data area (strings, random bytes
+/// and the like) is placed from the top of the stack down in the order it was pushed. Below it follow argc, argv,
+/// envp and the aux vector, each with its terminator, starting at a 16 byte aligned stack pointer.
+///
+/// Nothing is bound to the running process: the stack can be described for any top address without writing it,
+/// or serialized into any memory region
+#[derive(Debug)]
+pub struct InitialStack {
+    /// tells the references to data of this stack apart from the ones of other stacks
+    id: usize,
+    /// the stacks this one was cloned from and how many of their items it shares with each of them
+    origins: Vec<(usize, usize)>,
+    items: Vec<StackItem>,
+    argv: Vec<StackValue>,
+    envp: Vec<StackValue>,
+    auxv: Vec<(u64, StackValue)>,
+    size: Option<usize>,
+}
+
+/// The planned layout of an InitialStack below a given top address
+#[derive(Debug, Clone)]
+pub struct StackLayout {
+    /// the address right above the stack
+    pub top: usize,
+    /// the initial stack pointer, it points to argc
+    pub rsp: usize,
+    /// the pushed data and the address it is placed at, from the top of the stack down
+    pub data: Vec<(usize, Vec<u8>)>,
+    pub argv: Vec<usize>,
+    pub envp: Vec<usize>,
+    /// the aux vector without the terminating AT_NULL entry
+    pub auxv: Vec<(u64, usize)>,
+}
+
+impl StackLayout {
+    /// returns the number of bytes between the stack pointer and the top of the stack
+    pub fn size(&self) -> usize {
+        self.top - self.rsp
+    }
+}
+
+/// the id of the next InitialStack that is created
+static NEXT_STACK_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl Default for InitialStack {
+    fn default() -> Self {
+        InitialStack {
+            id: NEXT_STACK_ID.fetch_add(1, Ordering::Relaxed),
+            origins: Vec::new(),
+            items: Vec::new(),
+            argv: Vec::new(),
+            envp: Vec::new(),
+            auxv: Vec::new(),
+            size: None,
+        }
+    }
+}
+
+/// A clone gets an id of its own, so that the data pushed onto the clone and onto the original afterwards can not
+/// be mixed up. References to the data both of them share stay valid for the clone
+impl Clone for InitialStack {
+    fn clone(&self) -> Self {
+        let mut origins = self.origins.clone();
+        origins.push((self.id, self.items.len()));
+
+        InitialStack {
+            id: NEXT_STACK_ID.fetch_add(1, Ordering::Relaxed),
+            origins,
+            items: self.items.clone(),
+            argv: self.argv.clone(),
+            envp: self.envp.clone(),
+            auxv: self.auxv.clone(),
+            size: self.size,
+        }
+    }
+}
+
+impl InitialStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// places the bytes right below everything that was pushed before
+    pub fn push_bytes(&mut self, data: &[u8]) -> StackRef {
+        self.items.push(StackItem::Data(data.to_vec()));
+        StackRef { stack: self.id, idx: self.items.len() - 1 }
+    }
+
+    /// places a NUL terminated copy of the string right below everything that was pushed before. Just like for
+    /// execve(), the string does not have to be UTF-8
+    pub fn push_string<S: AsRef<OsStr>>(&mut self, string: S) -> StackRef {
+        let string = string.as_ref().as_bytes();
+        let mut data = Vec::with_capacity(string.len() + 1);
+        data.extend_from_slice(string);
+        data.push(0);
+        self.push_bytes(&data)
+    }
+
+    /// leaves the given number of bytes unused
+    pub fn pad(&mut self, len: usize) -> &mut Self {
+        self.items.push(StackItem::Pad(len));
+        self
+    }
+
+    /// aligns whatever is pushed next to the given power of two. Other alignments make describe() and write() fail
+    pub fn align(&mut self, align: usize) -> &mut Self {
+        self.items.push(StackItem::Align(align));
+        self
+    }
+
+    /// appends a pointer to argv, argc is the number of these pointers
+    pub fn arg<V: Into<StackValue>>(&mut self, value: V) -> &mut Self {
+        self.argv.push(value.into());
+        self
+    }
+
+    /// appends a pointer to envp
+    pub fn env<V: Into<StackValue>>(&mut self, value: V) -> &mut Self {
+        self.envp.push(value.into());
+        self
+    }
+
+    /// sets an entry of the aux vector. An entry that is already present keeps its position, new ones are appended.
+    /// The terminating AT_NULL entry is added automatically
+    pub fn aux<V: Into<StackValue>>(&mut self, key: u64, value: V) -> &mut Self {
+        let value = value.into();
+        match self.auxv.iter_mut().find(|(aux_key, _)| *aux_key == key) {
+            Some(entry) => entry.1 = value,
+            None => self.auxv.push((key, value)),
+        }
+        self
+    }
+
+    /// removes an entry from the aux vector
+    pub fn remove_aux(&mut self, key: u64) -> &mut Self {
+        self.auxv.retain(|(aux_key, _)| *aux_key != key);
+        self
+    }
+
+    /// applies the changes to the aux vector in the order they were given
+    pub fn apply_overrides(&mut self, overrides: &[AuxOverride]) -> &mut Self {
+        for aux_override in overrides {
+            match *aux_override {
+                AuxOverride::Set(key, val) => self.aux(key, val as usize),
+                AuxOverride::Drop(key) => self.remove_aux(key),
+            };
+        }
+        self
+    }
+
+    /// limits the stack to the given number of bytes. A stack that does not fit can not be described or written
+    pub fn set_size(&mut self, size: usize) -> &mut Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// lays out the stack below the given top address without writing anything
+    pub fn describe(&self, top: usize) -> Result<StackLayout, StackError> {
+        let mut cursor = top;
+        let mut addrs = Vec::with_capacity(self.items.len());
+        let mut data = Vec::new();
+
+        for item in self.items.iter() {
+            match item {
+                StackItem::Data(bytes) => {
+                    cursor = below(top, cursor, bytes.len())?;
+                    data.push((cursor, bytes.clone()));
+                },
+                StackItem::Pad(len) => cursor = below(top, cursor, *len)?,
+                StackItem::Align(align) if align.is_power_of_two() => cursor &= !(align - 1),
+                StackItem::Align(align) => return Err(StackError::BadAlignment(*align)),
+            }
+            addrs.push(cursor);
+        }
+
+        // argc, argv and envp with their NULL terminators and the aux vector with AT_NULL. STACK_ROUND() then aligns
+        // the stack pointer, which leaves the padding between the aux vector and the data area
+        let tables = (1 + self.argv.len() + 1 + self.envp.len() + 1) * 8 + (self.auxv.len() + 1) * 16;
+        let rsp = below(top, cursor, tables)? & !15;
+
+        let needed = top - rsp;
+        if let Some(size) = self.size {
+            if needed > size {
+                return Err(StackError::TooLarge { needed, size });
+            }
+        }
+
+        // references of the stacks this one was cloned from are only valid for the data that was pushed before cloning
+        let owned = |stack_ref: StackRef| {
+            let shared = if stack_ref.stack == self.id {
+                addrs.len()
+            } else {
+                self.origins.iter().find(|(id, _)| *id == stack_ref.stack).map_or(0, |(_, len)| *len)
+            };
+            stack_ref.idx < shared
+        };
+        let resolve = |value: &StackValue| match *value {
+            StackValue::Value(value) => Ok(value),
+            StackValue::Ref(stack_ref) if owned(stack_ref) => Ok(addrs[stack_ref.idx]),
+            StackValue::Ref(stack_ref) => Err(StackError::ForeignRef(stack_ref)),
+        };
+
+        Ok(StackLayout {
+            top,
+            rsp,
+            data,
+            argv: self.argv.iter().map(resolve).collect::<Result<_, _>>()?,
+            envp: self.envp.iter().map(resolve).collect::<Result<_, _>>()?,
+            auxv: self.auxv.iter().map(|(key, value)| Ok((*key, resolve(value)?))).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// lays out the stack below the given top address and writes it into the region, whose last byte is the one
+    /// right below top. The region can be the stack itself or a buffer that is mapped at that address later.
+    /// Everything between the stack pointer and top is overwritten, padding is zeroed
+    pub fn write(&self, region: &mut [u8], top: usize) -> Result<StackLayout, StackError> {
+        let layout = self.describe(top)?;
+        if layout.size() > region.len() {
+            return Err(StackError::TooLarge { needed: layout.size(), size: region.len() });
+        }
+
+        let start = region.len() - layout.size();
+        let stack = &mut region[start..];
+        for byte in stack.iter_mut() {
+            *byte = 0;
+        }
+
+        for (addr, bytes) in layout.data.iter() {
+            let offset = addr - layout.rsp;
+            stack[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        let mut tables: Vec<usize> = Vec::new();
+        tables.push(layout.argv.len());
+        tables.extend(layout.argv.iter());
+        tables.push(0x0);
+        tables.extend(layout.envp.iter());
+        tables.push(0x0);
+        for (key, val) in layout.auxv.iter() {
+            tables.push(*key as usize);
+            tables.push(*val);
+        }
+        tables.push(AT_NULL as usize);
+        tables.push(0x0);
+
+        for (idx, value) in tables.iter().enumerate() {
+            stack[idx * 8..idx * 8 + 8].copy_from_slice(&value.to_ne_bytes());
+        }
+
+        Ok(layout)
+    }
+}
+
+/// moves the cursor down by len bytes, unless that would leave the address space
+fn below(top: usize, cursor: usize, len: usize) -> Result<usize, StackError> {
+    cursor.checked_sub(len).ok_or(StackError::TooLarge { needed: (top - cursor).saturating_add(len), size: top })
+}
+
+
+/// maps the stack and an inaccessible guard gap below it and returns the start of the guard gap and the top of the stack. Just like the
+/// kernel, the top is placed randomly below STACK_TOP, unless randomization is disabled or every location
+/// that was tried is already in use. In that case mmap() chooses one
+fn map_stack(size: usize, executable: bool) -> Result<(usize, usize), StackError> {
     let stack_flags = {
         let mut map_flags = MapFlags::empty();
         map_flags.insert(MapFlags::MAP_PRIVATE);
         map_flags.insert(MapFlags::MAP_ANONYMOUS);
         map_flags.insert(MapFlags::MAP_STACK);
+        map_flags.insert(MapFlags::MAP_NORESERVE);
 
         map_flags
     };
 
-
     let stack_prot = {
         let mut prot_flags = ProtFlags::empty();
         prot_flags.insert(ProtFlags::PROT_WRITE);
         prot_flags.insert(ProtFlags::PROT_READ);
+        if executable {
+            prot_flags.insert(ProtFlags::PROT_EXEC);
+        }
 
         prot_flags
     };
 
-    let stack_size = 1024 * 256;
-    let stack_end = unsafe {
-        mmap(0 as *mut c_void, stack_size, stack_prot, stack_flags, -1, 0)
-            .expect("Failed to allocate stack!")
+    let size = (size + 0xfff) & !0xfff;
+    let total_size = size + STACK_GUARD_GAP;
+
+    let mut mapping = None;
+    if ElfLoad::randomization_enabled() {
+        for _ in 0..STACK_PLACEMENT_TRIES {
+            let top = STACK_TOP - ((rand::thread_rng().gen::<usize>() & STACK_RND_MASK) << 12);
+            let res = unsafe {
+                mmap((top - total_size) as *mut c_void, total_size, ProtFlags::PROT_NONE, stack_flags | map_fixed_noreplace(), -1, 0)
+            };
+            if let Ok(addr) = res {
+                mapping = Some(addr as usize);
+                break;
+            }
+        }
+    }
+
+    let mapping = match mapping {
+        Some(addr) => addr,
+        None => unsafe {
+            mmap(std::ptr::null_mut(), total_size, ProtFlags::PROT_NONE, stack_flags, -1, 0)
+                .map_err(StackError::Map)? as usize
+        },
     };
 
-    // the stack grows downward, so setup a stack pointer to the beginnings
-    let mut stack_pointer = stack_end as usize + stack_size;
-
-    // 16 byte align the Stack pointer
-    stack_pointer = (stack_pointer + 15) & (!15);
-
-    // Copy the current environment onto the stack!
-    let mut env: Vec<usize> = Vec::new();
-    for (env_name, env_val) in std::env::vars() {
-        // format the environment variable as it would actually look like in memory and explicitly add a 0byte
-        let env_var = format!("{}={}\0", env_name, env_val);
-        stack_pointer -= env_var.len();
-        env.push(stack_pointer);
-        write_data(stack_pointer, &env_var.as_bytes())
-    }
-
-    
-    // copy the contents of the program arguments onto the stack and build an argv[] pointer array
-    // ignore argv[1], as it is the program to be loaded and is interpreted by this program itself
-    let mut argv: Vec<usize> = Vec::new();
-    let mut args: Vec<String> = std::env::args().collect();
-    args.remove(1);
-    for arg in args.iter_mut().rev() {
-        stack_pointer -= arg.len() + 1; // +1 for a NULLBYTE
-        argv.push(stack_pointer);
-        let argv_bytes = unsafe {
-            arg.as_mut_vec()
-        };
-        argv_bytes.push(0); // push a nullbyte
-        write_data(stack_pointer, &argv_bytes);
+    // everything above the guard gap is the actual stack
+    unsafe {
+        mprotect((mapping + STACK_GUARD_GAP) as *mut c_void, size, stack_prot).map_err(StackError::Map)?;
     }
 
+    Ok((mapping, mapping + total_size))
+}
 
-    // after copying the argument contents and environment variables, 16 byte align the stack pointer
-    stack_pointer &= !0xf;
+/// the random amount arch_align_stack() moves the stack pointer down by on x86-64, before it 16 byte aligns it
+fn arch_align_stack_offset() -> usize {
+    if ElfLoad::randomization_enabled() {
+        rand::thread_rng().gen_range(0, STACK_ALIGN_RND)
+    } else {
+        0
+    }
+}
 
+/// sets up an initial stack according to the System-V x86 ABI and passes information such
+/// as the entry point and location of the program headers of the application to tbe loaded
+/// to the ELF Interpreter or the CSU routines.
+pub fn setup_stack(load_info: &LoadInfo, binary_load: &ElfLoad, interp_base: usize, config: &StackConfig) -> Result<NewStack, StackError> {
+    // the layout below follows the kernel's execve() exactly
+    let mut stack = InitialStack::new();
+    stack.set_size(config.size);
 
-    // place the platform string on the stack
-    stack_pointer -= "x86_64".len() + 1;
-    write_data(stack_pointer, "x86_64\0".as_bytes());
-    
-    // the next item are 16bytes of random data as a PRNG seed
-    let seed_bytes = rand::thread_rng().gen::<[u8; 16]>();
-    stack_pointer -= seed_bytes.len();
-    write_data(stack_pointer, &seed_bytes);
-    let prng_pointer = stack_pointer;
+    // bprm_mm_init() leaves room for a NULL pointer at the very top of the stack
+    stack.push_bytes(&[0u8; 8]);
 
-    
-    // allocate space for the AUX vectors
-    stack_pointer -= 0x120;
+    // copy_string_kernel() places the file name of the program right below it
+    let execfn = stack.push_string(&config.execfn);
 
-    // make space for the argv and envp char ** arrays + a NULL terminator for each of them
-    let pointers = (argv.len() + 1) + (env.len() + 1) + 1;
-    stack_pointer -= pointers * 8;
+    // copy_strings() then copies the environment and the arguments, starting with the last string of each. This
+    // way the strings end up in the same order in memory as their pointers in envp and argv
+    let mut env: Vec<StackRef> = config.env.iter().rev().map(|env_var| stack.push_string(env_var)).collect();
+    env.reverse();
+    let mut argv: Vec<StackRef> = config.args.iter().rev().map(|arg| stack.push_string(arg)).collect();
+    argv.reverse();
 
-    // align the pointer again
-    stack_pointer &= !15;
+    // create_elf_tables() starts out with arch_align_stack(), which moves the stack pointer down by a random amount
+    // below 8192 bytes and 16 byte aligns it
+    stack.pad(arch_align_stack_offset()).align(16);
 
-    // the current stack pointer is the one we will return!
-    let rsp = stack_pointer;
+    // place the platform string on the stack
+    let platform = stack.push_string("x86_64");
 
+    // the next item are 16bytes of random data as a PRNG seed
+    let seed_bytes = config.random_seed.unwrap_or_else(|| rand::thread_rng().gen::<[u8; 16]>());
+    let prng = stack.push_bytes(&seed_bytes);
 
-    // put argc on the stack
-    write_pointer(&mut stack_pointer, argv.len());
+    for arg in argv {
+        stack.arg(arg);
+    }
+    for env_var in env {
+        stack.env(env_var);
+    }
 
-    // write each of the argument pointers to the stack
-    for arg in argv.iter().rev() {
-        write_pointer(&mut stack_pointer, *arg);
+    // build the aux vector from the one the kernel gave us. This way the loaded program gets the same entries in
+    // the same order as a real process, only the values that describe the program itself are replaced
+    for (key, val) in kernel_auxv() {
+        let val: StackValue = match key {
+            // tell the CSU where to find the program headers of the binary to be loaded
+            // to do this, we pass a pointer to them, the size of an entry and the number of entries
+            AT_PHDR => binary_load.phdr_addr.into(),
+            AT_PHENT => PHENT_SIZE.into(),
+            AT_PHNUM => load_info.pheader_num.into(),
+
+            // base is the base address of the ELF Interpreter (ld.so). Static binaries, static-pie ones included,
+            // get 0 just like from the kernel
+            AT_BASE => interp_base.into(),
+
+            // the flags are hardcoded 0 by the kernel
+            AT_FLAGS => 0x0.into(),
+
+            // the entry point of this binary. It is used by (ld.so) to jump to the binary once relocations
+            // have been performed. The load bias is 0 for ET_EXEC binaries, so this is correct for both
+            // absolute and relative entry points
+            AT_ENTRY => binary_load.entry.into(),
+
+            // pointers to the strings and random bytes on the new stack
+            AT_RANDOM => prng.into(),
+            AT_EXECFN => execfn.into(),
+            AT_PLATFORM => platform.into(),
+
+            // the file descriptor binfmt_misc passed to the loader does not refer to the loaded program and x86-64
+            // does not have a base platform string that we would have to copy
+            AT_EXECFD | AT_BASE_PLATFORM => continue,
+
+            // everything else, e.g. the VDSO, hardware capabilities, credentials and rseq parameters, is passed on
+            _ => (val as usize).into(),
+        };
+        stack.aux(key, val);
     }
+    stack.apply_overrides(&config.auxv_overrides);
 
-    // place a NULL pointer to signal this is the end of the argv array
-    write_pointer(&mut stack_pointer, 0x0);
+    // measure the stack before anything is mapped, so that a huge environment or argument list fails cleanly. The
+    // top of the stack is page aligned wherever it ends up, so the layout needs the same space at STACK_TOP
+    stack.describe(STACK_TOP)?;
 
+    // allocate the new stack area and write the stack right below its top
+    let (stack_start, stack_top) = map_stack(config.size, config.executable)?;
+    let stack_bottom = stack_start + STACK_GUARD_GAP;
+    let region = unsafe {
+        std::slice::from_raw_parts_mut(stack_bottom as *mut u8, stack_top - stack_bottom)
+    };
+    let layout = stack.write(region, stack_top)?;
 
-    // write each of the environemnt pointers to the stack
-    for e in env.iter() {
-        write_pointer(&mut stack_pointer, *e);
-    }
+    // that's it! We should now have a valid and clean stack for executing the new program
+    Ok(NewStack {
+        rsp: layout.rsp,
+        start: stack_start,
+        end: stack_top,
+        layout,
+    })
+}
 
-    // place a NULL pointer to signify that this is the end of the environment pointer
-    write_pointer(&mut stack_pointer, 0x0);
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auxv::AT_PAGESZ;
+    use std::convert::TryInto;
 
-        // next are the AUX information needed for the ELF Interpreter and/or __libc_start_main
-    // we can derive most of them via libc's getauxval()
-    unsafe {
+    const TOP: usize = 0x10000;
 
-        // VDSO is a shared object mapped into userspace by the kernel that can be used by libc
-        write_aux_val(&mut stack_pointer, AT_SYSINFO_EHDR, libc::getauxval(AT_SYSINFO_EHDR));
-
-        // some generic architecture / processor specific value we derive from our own auxvector
-        write_aux_val(&mut stack_pointer, AT_HWCAP, libc::getauxval(AT_HWCAP));
-        write_aux_val(&mut stack_pointer, AT_PAGESZ, libc::getauxval(AT_PAGESZ));
-        write_aux_val(&mut stack_pointer, AT_CLKTCK, libc::getauxval(AT_CLKTCK)); 
-        write_aux_val(&mut stack_pointer, AT_HWCAP2, libc::getauxval(AT_HWCAP2));
-
-        // tell the CSU where to find the program headers of the binary to be loaded
-        // to do this, we pass a pointer to them, the size of an entry and the number of entries
-        write_aux_val(&mut stack_pointer, AT_PHDR, (load_address + load_info.pheader_off) as u64);
-        write_aux_val(&mut stack_pointer, AT_PHENT, PHENT_SIZE as u64);
-        write_aux_val(&mut stack_pointer, AT_PHNUM, load_info.pheader_num as u64);
-
-        // base is the base address of the ELF Interpreter (ld.so)
-        write_aux_val(&mut stack_pointer, AT_BASE, interp_base as u64);
-
-        // the flags are hardcoded 0 by the kernel
-        write_aux_val(&mut stack_pointer, AT_FLAGS, 0x0);
-
-        // the entry point of this binary. It is used by (ld.so) to jump to the binary once relocations 
-        // have been performed
-        // this might be relative or absolute, dependeing on the type of binary that is loaded!
-        match load_info.etype {
-            ElfType::ElfExec => write_aux_val(&mut stack_pointer, AT_ENTRY, load_info.entry_point as u64),
-            ElfType::ElfDyn => write_aux_val(&mut stack_pointer, AT_ENTRY, (load_address + load_info.entry_point) as u64)
-        }
-        
+    /// a stack with a single argument, environment variable and aux vector entry that all point to one string
+    fn small_stack() -> (InitialStack, StackRef) {
+        let mut stack = InitialStack::new();
+        stack.push_bytes(&[0u8; 8]);
+        let string = stack.push_string("/bin/true");
+        stack.align(16).arg(string).env(0x1234).aux(AT_EXECFN, string);
+        (stack, string)
+    }
 
-        // pass some generic info about the user running the process deriving from our own auxval
-        write_aux_val(&mut stack_pointer, AT_UID, libc::getauxval(AT_UID));
-        write_aux_val(&mut stack_pointer, AT_EUID, libc::getauxval(AT_EUID));
-        write_aux_val(&mut stack_pointer, AT_GID, libc::getauxval(AT_GID));
-        write_aux_val(&mut stack_pointer, AT_EGID, libc::getauxval(AT_EGID));
-        write_aux_val(&mut stack_pointer, AT_SECURE, libc::getauxval(AT_SECURE));
+    #[test]
+    fn describes_the_layout() {
+        let (stack, _) = small_stack();
+        let layout = stack.describe(TOP).unwrap();
 
-        // pass a pointer to the initial 16 bytes of random memory for use by libc
-        write_aux_val(&mut stack_pointer, AT_RANDOM, prng_pointer as u64);
-        
+        assert_eq!(layout.data, vec![(0xfff8, vec![0u8; 8]), (0xffee, b"/bin/true\0".to_vec())]);
+        assert_eq!(layout.argv, [0xffee]);
+        assert_eq!(layout.envp, [0x1234]);
+        assert_eq!(layout.auxv, [(AT_EXECFN, 0xffee)]);
 
-        // store a pointer to the program name here
-        write_aux_val(&mut stack_pointer, AT_EXECFN, argv[0] as u64);
+        // argc, argv, envp and their terminators plus two aux vector entries right below the aligned data
+        assert_eq!(layout.rsp, (0xffe0 - 5 * 8 - 2 * 16) & !15);
+        assert_eq!(layout.size(), TOP - layout.rsp);
+    }
 
-        // end the aux vector
-        write_aux_val(&mut stack_pointer, AT_NULL, 0x0);
-    };
-    
-    // that's it! We should now have a valid and clean stack for executing the new program
-    // return the current stack pointer so that we can return it!
-    rsp
-}
+    #[test]
+    fn writes_the_layout() {
+        let (mut stack, _) = small_stack();
+        stack.aux(AT_PAGESZ, 0x1000).remove_aux(AT_PAGESZ);
+        let mut region = vec![0xffu8; 0x200];
+        let layout = stack.write(&mut region, TOP).unwrap();
+
+        let start = region.len() - layout.size();
+        let word = |idx: usize| usize::from_ne_bytes(region[start + idx * 8..start + idx * 8 + 8].try_into().unwrap());
+        let words: Vec<usize> = (0..9).map(word).collect();
+        assert_eq!(words, [1, 0xffee, 0, 0x1234, 0, AT_EXECFN as usize, 0xffee, AT_NULL as usize, 0]);
+        assert_eq!(&region[region.len() - 0x12..region.len() - 8], b"/bin/true\0");
+        assert!(region[..start].iter().all(|byte| *byte == 0xff));
+    }
+
+    #[test]
+    fn pushes_strings_that_are_not_utf8() {
+        let mut stack = InitialStack::new();
+        stack.push_string(OsStr::from_bytes(b"BAD=\xff"));
+        assert_eq!(stack.describe(TOP).unwrap().data, vec![(TOP - 6, b"BAD=\xff\0".to_vec())]);
+    }
 
+    #[test]
+    fn rejects_stacks_that_do_not_fit() {
+        let (mut stack, _) = small_stack();
+        assert!(matches!(stack.describe(0x10), Err(StackError::TooLarge { .. })));
+        assert!(matches!(stack.write(&mut [0u8; 0x10], TOP), Err(StackError::TooLarge { needed: 0x70, size: 0x10 })));
 
+        stack.set_size(0x20);
+        assert!(matches!(stack.describe(TOP), Err(StackError::TooLarge { needed: 0x70, size: 0x20 })));
+    }
 
-fn write_data(sp: usize, data: &[u8]) {
-    unsafe {
-        libc::memcpy(sp as *mut c_void, data.as_ptr() as *const c_void, data.len());
+    #[test]
+    fn rejects_bad_alignments() {
+        let (mut stack, _) = small_stack();
+        stack.align(24);
+        assert!(matches!(stack.describe(TOP), Err(StackError::BadAlignment(24))));
     }
-}
 
-// writes an AUX val to the stack and advances the stack pointer
-fn write_aux_val(sp: &mut usize, aux_id: u64, aux_val: u64) {
-   
-    write_pointer(sp, aux_id as usize);
-    write_pointer(sp, aux_val as usize);
+    #[test]
+    fn rejects_foreign_references() {
+        let (_, foreign) = small_stack();
+        let (mut stack, _) = small_stack();
+        stack.arg(foreign);
+        assert!(matches!(stack.describe(TOP), Err(StackError::ForeignRef(stack_ref)) if stack_ref == foreign));
+
+        // a clone shares the references pushed so far, but not the ones pushed onto the clone afterwards
+        let (stack, string) = small_stack();
+        let mut clone = stack.clone();
+        clone.arg(string);
+        assert!(clone.describe(TOP).is_ok());
+        let pushed = clone.push_string("only on the clone");
+        let mut stack = stack;
+        stack.arg(pushed);
+        assert!(matches!(stack.describe(TOP), Err(StackError::ForeignRef(_))));
+    }
 
+    #[test]
+    fn rejects_references_of_diverged_clones() {
+        let (stack, string) = small_stack();
+        let mut first = stack.clone();
+        let mut second = stack.clone();
+        let first_only = first.push_string("first");
+        let second_only = second.push_string("second");
+
+        // both clones share the data of the original, including a clone of a clone
+        let mut nested = first.clone();
+        nested.arg(string).arg(first_only);
+        assert_eq!(nested.describe(TOP).unwrap().argv[1..], [0xffee, 0xffe0 - 6]);
+
+        // the item at the same index is a different one on the other clone
+        first.arg(second_only);
+        assert!(matches!(first.describe(TOP), Err(StackError::ForeignRef(stack_ref)) if stack_ref == second_only));
+        second.arg(first_only);
+        assert!(matches!(second.describe(TOP), Err(StackError::ForeignRef(stack_ref)) if stack_ref == first_only));
+    }
 }
-
-fn write_pointer(sp: &mut usize, value: usize) {
-    let ptr = *sp as *mut usize;
-    unsafe {
-        *ptr = value
-    };
-    *sp += 8;
-}
\ No newline at end of file
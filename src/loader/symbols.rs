@@ -0,0 +1,331 @@
+use crate::load_elf::ElfLoad;
+use crate::parse_elf::{
+    ElfError,
+    ElfSection,
+    LoadInfo,
+    SHT_DYNSYM,
+    SHT_SYMTAB
+};
+
+/// standard size of an Elf64_Sym entry
+const SIZE_OF_SYMBOL: usize = 24;
+
+/// section index of undefined symbols, i.e. symbols imported from another object
+const SHN_UNDEF: u16 = 0;
+
+/// values of the lower nibble of st_info
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const STT_COMMON: u8 = 5;
+const STT_TLS: u8 = 6;
+const STT_GNU_IFUNC: u8 = 10;
+
+/// values of the upper nibble of st_info
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STB_GNU_UNIQUE: u8 = 10;
+
+
+/// Represents an Elf64_Sym as found in an actual ELF file
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct Elf64Sym {
+    name:   u32,
+    info:   u8,
+    other:  u8,
+    shndx:  u16,
+    value:  u64,
+    size:   u64
+}
+
+/// the type of a symbol, as encoded in the lower nibble of st_info
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SymbolType {
+    NoType,
+    Object,
+    Func,
+    Section,
+    File,
+    Common,
+    Tls,
+    GnuIfunc,
+    Other(u8)
+}
+
+impl SymbolType {
+    pub fn from(info: u8) -> Self {
+        match info & 0xf {
+            STT_NOTYPE => SymbolType::NoType,
+            STT_OBJECT => SymbolType::Object,
+            STT_FUNC => SymbolType::Func,
+            STT_SECTION => SymbolType::Section,
+            STT_FILE => SymbolType::File,
+            STT_COMMON => SymbolType::Common,
+            STT_TLS => SymbolType::Tls,
+            STT_GNU_IFUNC => SymbolType::GnuIfunc,
+            other => SymbolType::Other(other)
+        }
+    }
+}
+
+/// the binding of a symbol, as encoded in the upper nibble of st_info
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SymbolBinding {
+    Local,
+    Global,
+    Weak,
+    GnuUnique,
+    Other(u8)
+}
+
+impl SymbolBinding {
+    pub fn from(info: u8) -> Self {
+        match info >> 4 {
+            STB_LOCAL => SymbolBinding::Local,
+            STB_GLOBAL => SymbolBinding::Global,
+            STB_WEAK => SymbolBinding::Weak,
+            STB_GNU_UNIQUE => SymbolBinding::GnuUnique,
+            other => SymbolBinding::Other(other)
+        }
+    }
+}
+
+/// A symbol defined by an ELF file. The value is the virtual address found in the file, which still
+/// needs to be rebased through the load bias of the image for ET_DYN files
+#[derive(Debug, Clone)]
+pub struct ElfSymbol {
+    pub name:       String,
+    pub value:      usize,
+    pub size:       usize,
+    pub stype:      SymbolType,
+    pub binding:    SymbolBinding,
+    pub shndx:      u16,
+}
+
+impl ElfSymbol {
+
+    /// returns true if the given (file) address lies within this symbol
+    fn contains(&self, addr: usize) -> bool {
+        if self.size == 0 {
+            addr == self.value
+        } else {
+            addr >= self.value && addr - self.value < self.size
+        }
+    }
+}
+
+
+/// All symbols defined by an ELF file, merged from .symtab and .dynsym
+pub struct SymbolTable {
+    /// all defined symbols, .symtab entries first so that they win over their .dynsym duplicates
+    symbols: Vec<ElfSymbol>,
+    /// indices into symbols of everything that describes code or data, sorted by address
+    by_addr: Vec<usize>,
+}
+
+impl SymbolTable {
+
+    /// Parses the .symtab and .dynsym sections of an ELF. Stripped files simply only yield their dynamic symbols
+    pub fn parse(load_info: &LoadInfo) -> Result<Self, ElfError> {
+        let mut symbols: Vec<ElfSymbol> = Vec::new();
+        for stype in [SHT_SYMTAB, SHT_DYNSYM].iter() {
+            for section in load_info.sections_of_type(*stype) {
+                Self::parse_section(load_info, section, &mut symbols)?;
+            }
+        }
+
+        // only symbols that actually describe a location in the image are useful for reverse lookups
+        let mut by_addr: Vec<usize> = symbols.iter()
+            .enumerate()
+            .filter(|(_, sym)| sym.value != 0 && sym.stype != SymbolType::Section && sym.stype != SymbolType::File && sym.stype != SymbolType::Tls)
+            .map(|(idx, _)| idx)
+            .collect();
+        by_addr.sort_by_key(|idx| symbols[*idx].value);
+
+        Ok(SymbolTable {
+            symbols,
+            by_addr,
+        })
+    }
+
+    /// parses a single SHT_SYMTAB or SHT_DYNSYM section and appends all defined symbols
+    fn parse_section(load_info: &LoadInfo, section: &ElfSection, symbols: &mut Vec<ElfSymbol>) -> Result<(), ElfError> {
+        let data = load_info.section_data(section)
+            .filter(|_| section.entsize == SIZE_OF_SYMBOL)
+            .ok_or_else(|| ElfError::BadSymbolTable(section.name.clone()))?;
+
+        // the sh_link field of a symbol table is the index of the string table holding the symbol names
+        let strtab = load_info.sections.get(section.link as usize)
+            .and_then(|strtab| load_info.section_data(strtab))
+            .ok_or_else(|| ElfError::BadStringTable(section.name.clone()))?;
+
+        for entry in data.chunks_exact(SIZE_OF_SYMBOL) {
+            let mut buffer_clone: [u8; SIZE_OF_SYMBOL] = [0; SIZE_OF_SYMBOL];
+            buffer_clone.copy_from_slice(entry);
+
+            let sym = unsafe {
+                std::mem::transmute::<[u8; SIZE_OF_SYMBOL], Elf64Sym>(buffer_clone)
+            };
+
+            // skip imports and the NULL symbol, they do not have an address within this image
+            if sym.shndx == SHN_UNDEF {
+                continue;
+            }
+
+            let name = strtab.get(sym.name as usize..)
+                .and_then(|name| name.iter().position(|&c| c == 0).map(|len| &name[..len]))
+                .ok_or_else(|| ElfError::BadStringTable(section.name.clone()))?;
+
+            symbols.push(ElfSymbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                value: sym.value as usize,
+                size: sym.size as usize,
+                stype: SymbolType::from(sym.info),
+                binding: SymbolBinding::from(sym.info),
+                shndx: sym.shndx,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// returns all defined symbols
+    pub fn symbols(&self) -> &[ElfSymbol] {
+        &self.symbols
+    }
+
+    /// looks up a symbol by its name. Global symbols are preferred over local symbols of the same name
+    pub fn lookup(&self, name: &str) -> Option<&ElfSymbol> {
+        let mut candidates = self.symbols.iter().filter(|sym| sym.name == name);
+        let first = candidates.next()?;
+        if first.binding != SymbolBinding::Local {
+            return Some(first);
+        }
+
+        Some(candidates.find(|sym| sym.binding != SymbolBinding::Local).unwrap_or(first))
+    }
+
+    /// finds the symbol that contains the given (file) address and returns it along with the offset
+    /// of the address into the symbol
+    pub fn lookup_addr(&self, addr: usize) -> Option<(&ElfSymbol, usize)> {
+        // find the first symbol that starts after the address and walk backwards from there, since
+        // several symbols (aliases) may start at the same address
+        let end = self.by_addr.partition_point(|idx| self.symbols[*idx].value <= addr);
+
+        self.by_addr[..end].iter()
+            .rev()
+            .map(|idx| &self.symbols[*idx])
+            .find(|sym| sym.contains(addr))
+            .map(|sym| (sym, addr - sym.value))
+    }
+
+    /// returns the runtime address of a symbol in an image that has been loaded by the loader
+    pub fn runtime_addr(&self, name: &str, load: &ElfLoad) -> Option<usize> {
+        self.lookup(name).map(|sym| load.load_bias + sym.value)
+    }
+
+    /// the reverse of runtime_addr: finds the symbol containing an address of a loaded image
+    pub fn lookup_runtime_addr(&self, addr: usize, load: &ElfLoad) -> Option<(&ElfSymbol, usize)> {
+        self.lookup_addr(addr.checked_sub(load.load_bias)?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_elf::{self, SHT_STRTAB};
+    use crate::parse_elf::tests::{elf_image, elf_image_with_sections, load_segment, TestSection, BASE};
+
+    const BASE_ADDR: usize = BASE as usize;
+
+    /// the .text section of the test image
+    const TEXT: u16 = 1;
+
+    const STRTAB: &[u8] = b"\0dup\0main\0label\0";
+
+    fn symbol(name: u32, binding: u8, stype: u8, value: usize, size: usize) -> [u8; SIZE_OF_SYMBOL] {
+        let sym = Elf64Sym { name, info: (binding << 4) | stype, other: 0, shndx: TEXT, value: value as u64, size: size as u64 };
+        unsafe {
+            std::mem::transmute::<Elf64Sym, [u8; SIZE_OF_SYMBOL]>(sym)
+        }
+    }
+
+    /// a local and a global "dup", "main" as an alias of the local "dup" and "label", a symbol without a size
+    fn symbol_table() -> SymbolTable {
+        let symbols = [
+            [0u8; SIZE_OF_SYMBOL],
+            symbol(1, STB_LOCAL, STT_FUNC, BASE_ADDR + 0x100, 0x10),
+            symbol(5, STB_GLOBAL, STT_FUNC, BASE_ADDR + 0x100, 0x20),
+            symbol(10, STB_LOCAL, STT_NOTYPE, BASE_ADDR + 0x180, 0),
+            symbol(1, STB_GLOBAL, STT_OBJECT, BASE_ADDR + 0x200, 0x10),
+        ].concat();
+        let image = elf_image_with_sections(&[load_segment()], &[
+            TestSection { name: ".symtab", stype: SHT_SYMTAB, link: 4, entsize: SIZE_OF_SYMBOL as u64, data: &symbols },
+            TestSection { name: ".strtab", stype: SHT_STRTAB, link: 0, entsize: 0, data: STRTAB },
+        ]);
+
+        SymbolTable::parse(&parse_elf::parse_elf_bytes(&image).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn lookup_prefers_global_symbols() {
+        let table = symbol_table();
+        assert_eq!(table.symbols().len(), 4);
+
+        let dup = table.lookup("dup").unwrap();
+        assert_eq!((dup.value, dup.binding, dup.stype), (BASE_ADDR + 0x200, SymbolBinding::Global, SymbolType::Object));
+        assert_eq!(table.lookup("label").unwrap().binding, SymbolBinding::Local);
+        assert!(table.lookup("missing").is_none());
+    }
+
+    #[test]
+    fn lookup_addr_handles_aliases_and_symbols_without_size() {
+        let table = symbol_table();
+        let lookup = |addr| table.lookup_addr(addr).map(|(sym, offset)| (sym.name.as_str(), offset));
+
+        // the local "dup" and "main" both start at the same address, the alias that is defined last wins
+        assert_eq!(lookup(BASE_ADDR + 0x108), Some(("main", 0x8)));
+        // only "main" is large enough to contain this address
+        assert_eq!(lookup(BASE_ADDR + 0x118), Some(("main", 0x18)));
+        assert_eq!(lookup(BASE_ADDR + 0x120), None);
+
+        // a symbol without a size only contains its own address
+        assert_eq!(lookup(BASE_ADDR + 0x180), Some(("label", 0)));
+        assert_eq!(lookup(BASE_ADDR + 0x181), None);
+        assert_eq!(lookup(BASE_ADDR), None);
+    }
+
+    #[test]
+    fn runtime_addresses_are_rebased() {
+        let table = symbol_table();
+        let load = ElfLoad {
+            load_addr: 0x7f0000000000 + BASE_ADDR,
+            load_bias: 0x7f0000000000,
+            load_end: 0x7f0000000000 + BASE_ADDR + 0x1000,
+            entry: 0,
+            phdr_addr: 0,
+            ranges: Vec::new(),
+            segments: Vec::new(),
+        };
+
+        let main = table.runtime_addr("main", &load).unwrap();
+        assert_eq!(main, 0x7f0000000000 + BASE_ADDR + 0x100);
+        let (sym, offset) = table.lookup_runtime_addr(main + 4, &load).unwrap();
+        assert_eq!((sym.name.as_str(), offset), ("main", 4));
+        assert!(table.lookup_runtime_addr(0x1000, &load).is_none());
+    }
+
+    #[test]
+    fn stripped_files_have_no_symbols() {
+        let load_info = parse_elf::parse_elf_bytes(&elf_image(&[load_segment()])).unwrap();
+        let table = SymbolTable::parse(&load_info).unwrap();
+        assert!(table.symbols().is_empty());
+        assert!(table.lookup("main").is_none());
+        assert!(table.lookup_addr(BASE_ADDR + 0x100).is_none());
+    }
+}
@@ -0,0 +1,238 @@
+extern crate libc;
+extern crate nix;
+use nix::sys::mman::{
+    mmap,
+    mprotect,
+    ProtFlags,
+    MapFlags
+};
+use core::ffi::c_void;
+use std::arch::global_asm;
+use std::fmt;
+use std::io;
+
+use crate::exec_reset;
+use crate::load_elf::{PAGE_SIZE, TASK_SIZE};
+use crate::maps::{self, Mapping};
+
+/// the byte sequence of `syscall; ret`
+const SYSCALL_RET: [u8; 3] = [0x0f, 0x05, 0xc3];
+
+
+// The trampoline that finishes a userland exec. It is copied to a page of its own, so it must not reference
+// anything outside of itself. It receives a pointer to the UlExecArgs in rdi, switches to the new stack,
+// unmaps all the given ranges and then unmaps its own page through a `syscall; ret` gadget. The return
+// address of the gadget is the entry point, which the trampoline pushed onto the new stack. Without a gadget
+// the page of the trampoline stays mapped
+global_asm!("
+    .pushsection .text
+    .globl ul_exec_trampoline
+    .globl ul_exec_trampoline_end
+ul_exec_trampoline:
+    mov rsp, [rdi]
+    push qword ptr [rdi + 8]
+
+    mov r12, rdi
+    mov r13, [r12 + 40]
+    lea r14, [r12 + 48]
+2:
+    test r13, r13
+    jz 3f
+    mov eax, 11
+    mov rdi, [r14]
+    mov rsi, [r14 + 8]
+    syscall
+    add r14, 16
+    dec r13
+    jmp 2b
+3:
+    mov r11, [r12 + 16]
+    mov rdi, [r12 + 24]
+    mov rsi, [r12 + 32]
+
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+
+    test r11, r11
+    jz 4f
+    mov eax, 11
+    jmp r11
+4:
+    xor edi, edi
+    xor esi, esi
+    ret
+ul_exec_trampoline_end:
+    .popsection
+");
+
+extern "C" {
+    static ul_exec_trampoline: u8;
+    static ul_exec_trampoline_end: u8;
+}
+
+/// The arguments of the trampoline, followed by `count` (start, length) pairs to unmap. The offsets are
+/// hardcoded in the trampoline
+#[repr(C)]
+struct UlExecArgs {
+    rsp: usize,
+    entry: usize,
+    gadget: usize,
+    page: usize,
+    page_len: usize,
+    count: usize,
+}
+
+
+/// Everything that can go wrong while preparing a userland exec
+#[derive(Debug)]
+pub enum UlExecError {
+    /// /proc/self/maps could not be read
+    Maps(io::Error),
+    /// the page of the trampoline could not be mapped
+    Map(nix::Error),
+    /// the ranges to unmap do not fit into the page of the trampoline
+    TooManyRanges(usize),
+}
+
+impl fmt::Display for UlExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UlExecError::Maps(err) => write!(f, "failed to read /proc/self/maps: {}", err),
+            UlExecError::Map(err) => write!(f, "failed to map the trampoline: {}", err),
+            UlExecError::TooManyRanges(count) => write!(f, "{} ranges to unmap do not fit into the trampoline", count),
+        }
+    }
+}
+
+impl std::error::Error for UlExecError {}
+
+
+/// A prepared userland exec. Jumping to it unmaps everything that belongs to the loader
+pub struct Trampoline {
+    code: usize,
+    args: usize,
+}
+
+impl Trampoline {
+    /// Prepares a trampoline that unmaps everything but the given ranges (the loaded images and the new stack)
+    /// and the special mappings of the kernel, such as the vDSO. It then starts the program with the given stack
+    /// pointer and entry point. Nothing may be mapped after this, as it would survive the exec
+    pub fn prepare(keep: &[(usize, usize)], rsp: usize, entry: usize) -> Result<Self, UlExecError> {
+        let mut map_flags = MapFlags::empty();
+        map_flags.insert(MapFlags::MAP_PRIVATE);
+        map_flags.insert(MapFlags::MAP_ANONYMOUS);
+
+        let page = unsafe {
+            mmap(std::ptr::null_mut(), PAGE_SIZE, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, map_flags, -1, 0)
+                .map_err(UlExecError::Map)? as usize
+        };
+
+        let mappings = maps::read_maps().map_err(UlExecError::Maps)?;
+
+        // the kernel keeps writing to the rseq area of the loader's libc, which is about to be unmapped
+        exec_reset::unregister_rseq();
+
+        // everything that is not kept is unmapped, no matter whether it is mapped right now. This way the loader's
+        // heap is gone even if it grows after /proc/self/maps was read
+        let mut kept: Vec<(usize, usize)> = keep.iter()
+            .map(|(start, end)| (start & !(PAGE_SIZE - 1), (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)))
+            .chain(mappings.iter().filter(|mapping| mapping.is_kernel_mapping()).map(|mapping| (mapping.start, mapping.end)))
+            .chain(std::iter::once((page, page + PAGE_SIZE)))
+            .filter(|(start, _)| *start < TASK_SIZE)
+            .collect();
+        kept.sort_unstable();
+
+        let mut unmap: Vec<(usize, usize)> = Vec::new();
+        let mut cursor = 0;
+        for (start, end) in kept {
+            if start > cursor {
+                unmap.push((cursor, start - cursor));
+            }
+            cursor = cursor.max(end);
+        }
+        if cursor < TASK_SIZE {
+            unmap.push((cursor, TASK_SIZE - cursor));
+        }
+
+        // without a gadget the page of the trampoline stays mapped
+        let gadget = find_gadget(&mappings, keep).unwrap_or(0);
+
+        // the page holds the code of the trampoline, followed by its arguments
+        let code_start = std::ptr::addr_of!(ul_exec_trampoline) as usize;
+        let code_end = std::ptr::addr_of!(ul_exec_trampoline_end) as usize;
+        let code_len = code_end - code_start;
+        let args_offset = (code_len + 15) & !15;
+        let args_len = std::mem::size_of::<UlExecArgs>() + unmap.len() * 16;
+        if args_offset + args_len > PAGE_SIZE {
+            return Err(UlExecError::TooManyRanges(unmap.len()));
+        }
+
+        let args = UlExecArgs {
+            rsp,
+            entry,
+            gadget,
+            page,
+            page_len: PAGE_SIZE,
+            count: unmap.len(),
+        };
+
+        unsafe {
+            libc::memcpy(page as *mut c_void, code_start as *const c_void, code_len);
+            std::ptr::write((page + args_offset) as *mut UlExecArgs, args);
+            let ranges = (page + args_offset + std::mem::size_of::<UlExecArgs>()) as *mut usize;
+            for (idx, (start, len)) in unmap.iter().enumerate() {
+                *ranges.add(idx * 2) = *start;
+                *ranges.add(idx * 2 + 1) = *len;
+            }
+
+            mprotect(page as *mut c_void, PAGE_SIZE, ProtFlags::PROT_READ | ProtFlags::PROT_EXEC).map_err(UlExecError::Map)?;
+
+            // a real exec makes the new stack the one /proc/self/maps labels as [stack]. This requires CAP_SYS_RESOURCE,
+            // without it the label is simply missing
+            libc::prctl(libc::PR_SET_MM, libc::PR_SET_MM_START_STACK, rsp, 0, 0);
+        }
+
+        Ok(Trampoline {
+            code: page,
+            args: page + args_offset,
+        })
+    }
+
+    /// Jumps to the trampoline, which never returns
+    ///
+    /// # Safety
+    ///
+    /// Everything that is not kept is gone afterwards, the loaded program must be ready to run
+    pub unsafe fn jump(self) -> ! {
+        let trampoline: extern "C" fn(usize) -> ! = std::mem::transmute(self.code);
+        trampoline(self.args)
+    }
+}
+
+/// searches a `syscall; ret` gadget in the executable mappings that survive the exec: the vDSO first and the
+/// loaded images after that
+fn find_gadget(mappings: &[Mapping], keep: &[(usize, usize)]) -> Option<usize> {
+    let vdso = mappings.iter().filter(|mapping| mapping.path == "[vdso]");
+    let images = mappings.iter().filter(|mapping| keep.iter().any(|(start, end)| mapping.overlaps(*start, *end)));
+
+    vdso.chain(images)
+        .filter(|mapping| mapping.readable && mapping.executable && !mapping.writable)
+        .find_map(|mapping| {
+            let code = unsafe {
+                std::slice::from_raw_parts(mapping.start as *const u8, mapping.end - mapping.start)
+            };
+            code.windows(SYSCALL_RET.len())
+                .position(|window| window == SYSCALL_RET)
+                .map(|offset| mapping.start + offset)
+        })
+}